@@ -0,0 +1,2 @@
+//! HTTP server of Rust TalkBack chat
+pub mod server;
@@ -1,283 +1,576 @@
-use std::io::stdin;
-use std::sync::{Mutex, Arc};
-use std::collections::HashMap;
+use std::io::{stdin, IsTerminal};
+use std::sync::{Mutex, Arc, PoisonError};
+use std::sync::mpsc::{self, Sender};
 use std::fs;
-use std::str;
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "tls")]
+use std::env;
 
-use server::Server;
+use serde::Deserialize;
+use serde_json::json;
+use talkback::server::{Config, Form, Next, Request, RequestError, Response, RouteOptions, Server, ServerEvent, StaticFiles, Status, WebSocket, WebSocketHandler, WebSocketMessage};
+#[cfg(feature = "tls")]
+use talkback::server::Certificates;
 
-use crate::server::RequestError;
 use crate::sessions::AnonymSession;
 use crate::sessions::SessionError;
+use crate::message::Message;
 
-mod server;
 mod sessions;
 mod user;
 mod message;
 
+/// Optional file with settings of server (it's read again on SIGHUP)
+const SETTINGS_FILE: &str = "talkback.json";
+
+/// Time which requests get to finish on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximal time which request of messages can wait for new one
+const MAX_MESSAGES_WAIT: Duration = Duration::from_secs(30);
+
 fn main() {
     let session = Arc::new(Mutex::new(AnonymSession::new()));
 
-    let server = Server::new("0.0.0.0:8080", 5);
+    #[cfg(feature = "tls")]
+    let certificates = certificates();
 
-    // 404 error handler
-    server.add_error_handler(RequestError::NotFound, Box::new(|_, _, _| {
-        let mut headers = Vec::new();
-        let body = fs::read_to_string("htdocs/404.html").unwrap();
+    let server = Server::with_config("0.0.0.0:8080", Config {
+        #[cfg(feature = "tls")]
+        tls: certificates.clone(),
+        ..Settings::load().config()
+    });
 
-        headers.push(String::from("HTTP/1.1 404 Not Found"));
-        headers.push(String::from("Content-type: text/html; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+    // Error handlers (404, 405, 400, 408, 413, 431, 503 and 500)
+    add_error_pages(&server);
 
-        (
-            headers,
-            body,
-        )
-    }));
+    // Homepage
+    add_pages(&server);
 
-    // 400 error handler
-    server.add_error_handler(RequestError::BadRequest, Box::new(|_, _, _| {
-        let mut headers = Vec::new();
-        let body = fs::read_to_string("htdocs/400.html").unwrap();
+    // API
+    add_api(&server, &session);
 
-        headers.push(String::from("HTTP/1.1 400 Bad Request"));
-        headers.push(String::from("Content-type: text/html; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+    println!("Rust TalkBack Server");
 
-        (
-            headers,
-            body,
-        )
-    }));
+    let (control_tx, control_rx) = mpsc::channel();
 
-    // 503 error handler
-    server.add_error_handler(RequestError::ServiceUnavailable, Box::new(|_, _, _| {
-        let mut headers = Vec::new();
-        let body = fs::read_to_string("htdocs/503.html").unwrap();
+    // Enter works only in terminal (stdin of service is empty)
+    if stdin().is_terminal() {
+        println!("Press Enter to shutdown...");
 
-        headers.push(String::from("HTTP/1.1 503 Service Unavailable"));
-        headers.push(String::from("Content-type: text/html; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+        let control_tx = control_tx.clone();
 
-        (
-            headers,
-            body,
-        )
-    }));
+        thread::spawn(move || {
+            let _ = stdin().read_line(&mut String::new());
+            let _ = control_tx.send(Control::Shutdown);
+        });
+    }
 
-    // Homepage handler
-    server.add_handler("GET", "/", Box::new(|_, _, _| {
-        println!("get homepage");
+    #[cfg(unix)]
+    listen_signals(control_tx);
 
-        let mut headers = Vec::new();
-        let body = fs::read_to_string("htdocs/index.html").unwrap();
+    #[cfg(not(unix))]
+    drop(control_tx);
 
-        headers.push(String::from("HTTP/1.1 200 OK"));
-        headers.push(String::from("Content-type: text/html; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+    for control in control_rx.iter() {
+        match control {
+            Control::Reload => {
+                println!("i: reloading settings");
 
-        (
-            headers,
-            body,
-        )
-    }));
+                server.reload(Settings::load().config());
+                add_error_pages(&server);
 
-    // API
-    // Sign up
-    let session_copy_1 = Arc::clone(&session);
-    server.add_handler("POST", "/api/register", Box::new(move |_, _, request_body| {
-        println!("post api/register");
+                #[cfg(feature = "tls")]
+                if let Some(certificates) = &certificates {
+                    if let Err(error) = certificates.reload() {
+                        println!("e: can't reload certificates: {}", error);
+                    }
+                }
+
+                // Static files are read on every request, so they are up to date
+            },
+            Control::Shutdown => break,
+        }
+    }
+
+    // Requests which are being processed are finished (long polls wait for new message up to their deadline)
+    let report = server.shutdown(SHUTDOWN_TIMEOUT);
+
+    println!("i: server is stopped: {:?}", report);
 
-        let mut headers = Vec::new();
-        let body: String;
+    // Handlers of detached threads can keep session, so users are saved here (even if some handler was panicked with lock)
+    let saved = session.lock().unwrap_or_else(PoisonError::into_inner).save();
+
+    if let Err(error) = saved {
+        println!("e: can't write to users storage: {}", error);
+    }
+}
+
+/// Add handlers of chat API which share `session`
+fn add_api(server: &Server, session: &Arc<Mutex<AnonymSession>>) {
+    // Every API request is logged (e.g. `post api/register`)
+    server.add_middleware("/api", |request: &mut Request, next: &Next| {
+        println!("{} {}", request.method().to_lowercase(), request.path().trim_start_matches('/'));
+
+        next.run(request)
+    });
+
+    // Sign up
+    let session_copy_1 = Arc::clone(session);
+    server.add_handler("POST", "/api/register", move |request: &Request| {
+        let params = match Params::from_body(request) {
+            Ok(e) => e,
+            Err(response) => return response,
+        };
 
         let mut session = session_copy_1.lock().unwrap();
-        let params = params_from_body(request_body);
 
-        match session.register(
-            params.get("login").or(Some(&String::new())).unwrap(),
-            params.get("password").or(Some(&String::new())).unwrap()
-        ) {
+        match session.register(&params.login, &params.password) {
             Ok(_) => {
-                headers.push(String::from("HTTP/1.1 201 Created"));                
-                body = format!("{{\"result\":\"{}\"}}", "ok");
+                println!("i: user {} was registered", params.login);
 
-                println!("i: user {} was registered", params.get("login").unwrap());
+                Response::new(Status::Created).with_json(&json!({ "result": "ok" }))
             },
             Err(e) => {
-                headers.push(String::from("HTTP/1.1 400 Bad Request"));
-
                 let error = match e {
-                    SessionError::EmptyLogin => String::from("Empty login!"),
-                    SessionError::LoginExists => String::from("Login exists!"),
-                    SessionError::EmptyPassword => String::from("Empty password!"),
-                    SessionError::PasswordTooSmall => String::from("Password too small!"),
-                    _ => String::from("Unknown error!"),
+                    SessionError::EmptyLogin => "Empty login!",
+                    SessionError::LoginExists => "Login exists!",
+                    SessionError::EmptyPassword => "Empty password!",
+                    SessionError::PasswordTooSmall => "Password too small!",
+                    _ => "Unknown error!",
                 };
 
-                body = format!("{{\"result\":\"{}\"}}", error);
+                Response::new(Status::BadRequest).with_json(&json!({ "result": error }))
             },
         }
-
-        headers.push(String::from("Content-type: application/json; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
-
-        (
-            headers,
-            body,
-        )
-    }));
+    });
 
     // Sign in
-    let session_copy_2 = Arc::clone(&session);
-    server.add_handler("POST", "/api/auth", Box::new(move |_, _, request_body| {
-        println!("post api/auth");
-
-        let mut headers = Vec::new();
-        let body: String;
+    let session_copy_2 = Arc::clone(session);
+    server.add_handler("POST", "/api/auth", move |request: &Request| {
+        let params = match Params::from_body(request) {
+            Ok(e) => e,
+            Err(response) => return response,
+        };
 
         let mut session = session_copy_2.lock().unwrap();
-        let params = params_from_body(request_body);
 
-        match session.auth(
-            params.get("login").or(Some(&String::new())).unwrap(),
-            params.get("password").or(Some(&String::new())).unwrap()
-        ) {
+        match session.auth(&params.login, &params.password) {
             Ok(_) => {
-                headers.push(String::from("HTTP/1.1 200 Ok"));                
-                body = format!("{{\"result\":\"{}\"}}", "ok");
+                println!("i: user {} was authed", params.login);
 
-                println!("i: user {} was authed", params.get("login").unwrap());
+                Response::new(Status::Ok).with_json(&json!({ "result": "ok" }))
             },
             Err(e) => {
-                headers.push(String::from("HTTP/1.1 400 Bad Request"));
-
                 let error = match e {
-                    SessionError::EmptyLogin => String::from("Empty login!"),
-                    SessionError::EmptyPassword => String::from("Empty password!"),
-                    SessionError::LoginNotFound => String::from("Login not found!"),
-                    SessionError::AuthFailed => String::from("Auth failed!"),
-                    _ => String::from("Unknown error!"),
+                    SessionError::EmptyLogin => "Empty login!",
+                    SessionError::EmptyPassword => "Empty password!",
+                    SessionError::LoginNotFound => "Login not found!",
+                    SessionError::AuthFailed => "Auth failed!",
+                    _ => "Unknown error!",
                 };
 
-                body = format!("{{\"result\":\"{}\"}}", error);
+                Response::new(Status::BadRequest).with_json(&json!({ "result": error }))
             },
         }
+    });
+
+    // Get messages list (sign in required).
+    // With `after` only newer messages are returned, with `wait` request waits until they exist or time expires.
+    let session_copy_3 = Arc::clone(session);
+    server.add_handler("GET", "/api/messages", move |request: &Request| {
+        let params = Params::from_form(request.query());
+
+        // Id of the last possible message has no next one, so it's invalid too
+        let offset = match request.query().get("after") {
+            Some(after) => match after.parse::<usize>().ok().and_then(|e| e.checked_add(1)) {
+                Some(e) => e,
+                None => return Response::new(Status::BadRequest).with_json(&json!({ "result": "Invalid after!" })),
+            },
+            None => 0,
+        };
+
+        let wait = match request.query().get("wait") {
+            Some(wait) => match wait.parse::<u64>() {
+                Ok(e) => Duration::from_secs(e).min(MAX_MESSAGES_WAIT),
+                Err(_) => return Response::new(Status::BadRequest).with_json(&json!({ "result": "Invalid wait!" })),
+            },
+            None => Duration::from_secs(0),
+        };
 
-        headers.push(String::from("Content-type: application/json; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+        let mut session = session_copy_3.lock().unwrap();
 
-        (
-            headers,
-            body,
-        )
-    }));
+        let valid_session = match session.auth(&params.login, &params.password) {
+            Ok(e) => e,
+            Err(_) => return Response::new(Status::Unauthorized).with_json(&json!({ "result": "auth failed" })),
+        };
 
-    // Get messages list (sign in required)
-    let session_copy_3 = Arc::clone(&session);
-    server.add_handler("GET", "/api/messages", Box::new(move |params, _, _| {
-        println!("get api/messages");
+        println!("i: user {} requested messages", params.login);
 
-        let mut headers = Vec::new();
-        let body: String;
+        let messages = valid_session.get_messages(offset);
 
-        let mut session = session_copy_3.lock().unwrap();
+        if !messages.is_empty() || wait.as_secs() == 0 {
+            return Response::new(Status::Ok).with_json(&json!({ "result": messages }));
+        }
 
-        match session.auth(
-            params.get("login").or(Some(&String::new())).unwrap(),
-            params.get("password").or(Some(&String::new())).unwrap()
-        ) {
-            Ok(valid_session) => {
-                let messages = valid_session.get_messages(0).iter()
-                    .map(|message| message.format())
-                    .collect::<Vec<String>>().join("<br />");
+        // Request waits for the next message in event loop with no busy worker thread (empty list is sent if there is no one in time)
+        let session_copy = Arc::clone(&session_copy_3);
 
-                headers.push(String::from("HTTP/1.1 200 Ok"));  
-                body = format!("{{\"result\":\"{}\"}}", messages);
+        Response::new(Status::Ok)
+            .with_json(&json!({ "result": messages }))
+            .deferred(wait, move |deferred| {
+                let mut session = session_copy.lock().unwrap();
 
-                println!("i: user {} requested messages", params.get("login").unwrap());
-            },
-            Err(_) => {
-                headers.push(String::from("HTTP/1.1 401 Unauthorized"));
-                body = format!("{{\"result\":\"{}\"}}", "auth failed");
-            }
-        };
+                let valid_session = match session.auth(&params.login, &params.password) {
+                    Ok(e) => e,
+                    Err(_) => {
+                        let _ = deferred.respond(Response::new(Status::Unauthorized).with_json(&json!({ "result": "auth failed" })));
+                        return;
+                    },
+                };
 
-        headers.push(String::from("Content-type: application/json; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+                // Message could be sent after the first check (subscription is under the same lock, so nothing is lost)
+                let messages = valid_session.get_messages(offset);
 
-        (
-            headers,
-            body,
-        )
-    }));
+                if !messages.is_empty() {
+                    let _ = deferred.respond(Response::new(Status::Ok).with_json(&json!({ "result": messages })));
+                    return;
+                }
 
-    // Send message (sign in required)
-    let session_copy_4 = Arc::clone(&session);
-    server.add_handler("POST", "/api/message", Box::new(move |_, _, request_body| {
-        println!("post api/message");
+                let handle = deferred.clone();
+
+                // Subscriber of request which got empty list at deadline is removed later
+                valid_session.subscribe(move || handle.is_open(), move |message| {
+                    let _ = deferred.respond(Response::new(Status::Ok).with_json(&json!({ "result": [message] })));
+                    false
+                });
+            })
+    });
+
+    // Export messages history as text file (sign in required, download can be resumed,
+    // so it isn't compressed)
+    let session_copy_5 = Arc::clone(session);
+    server.add_handler_with("GET", "/api/export", move |request: &Request| {
+        let params = Params::from_form(request.query());
+        let mut session = session_copy_5.lock().unwrap();
+
+        match session.auth(&params.login, &params.password) {
+            Ok(valid_session) => {
+                let history = valid_session.get_messages(0).iter()
+                    .map(|message| format!("{}\n", message.format_text()))
+                    .collect::<String>();
+
+                Response::new(Status::Ok)
+                    .with_header("Content-Type", "text/plain; charset=utf-8")
+                    .with_header("Content-Disposition", "attachment; filename=\"talkback.txt\"")
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_body(history)
+            },
+            Err(_) => Response::new(Status::Unauthorized).with_json(&json!({ "result": "auth failed" })),
+        }
+    }, RouteOptions {
+        compress: false,
+    });
 
-        let mut headers = Vec::new();
-        let body: String;
+    // Send message (sign in required)
+    let session_copy_4 = Arc::clone(session);
+    server.add_handler("POST", "/api/message", move |request: &Request| {
+        let params = match Params::from_body(request) {
+            Ok(e) => e,
+            Err(response) => return response,
+        };
 
         let mut session = session_copy_4.lock().unwrap();
-        let params = params_from_body(request_body);
 
-        match session.auth(
-            params.get("login").or(Some(&String::new())).unwrap(),
-            params.get("password").or(Some(&String::new())).unwrap()
-        ) {
+        match session.auth(&params.login, &params.password) {
             Ok(valid_session) => {
-                valid_session.add_message(
-                    params.get("login").unwrap(),
-                    params.get("message").unwrap()
-                );
+                let message = valid_session.add_message(&params.login, &params.message);
 
-                headers.push(String::from("HTTP/1.1 201 Created"));  
-                body = format!("{{\"result\":\"{}\"}}", "ok");
+                println!("i: message was sent: {}", message.format());
 
-                println!("i: user {} sent a message: {}",
-                    params.get("login").unwrap(),
-                    params.get("message").unwrap()
-                );
+                Response::new(Status::Created).with_json(&json!({ "result": "ok" }))
             },
-            Err(err) => {
-                headers.push(String::from("HTTP/1.1 401 Unauthorized"));
-                body = format!("{{\"result\":\"{:?}\"}}", err);
+            Err(err) => Response::new(Status::Unauthorized).with_json(&json!({ "result": format!("{:?}", err) })),
+        }
+    });
+
+    // Real-time delivery of new messages (sign in required)
+    let session_copy_5 = Arc::clone(session);
+    server.add_handler("GET", "/api/ws", move |request: &Request| {
+        let params = Params::from_form(request.query());
+
+        if let Err(err) = session_copy_5.lock().unwrap().auth(&params.login, &params.password) {
+            return Response::new(Status::Unauthorized).with_json(&json!({ "result": format!("{:?}", err) }));
+        }
+
+        println!("i: user {} connected to chat", params.login);
+
+        Response::upgrade(request, ChatSocket {
+            session: Arc::clone(&session_copy_5),
+            login: params.login,
+            password: params.password,
+        })
+    });
+
+    // Stream of new messages for EventSource (messages after `Last-Event-ID` are sent first on reconnect)
+    let session_copy_6 = Arc::clone(session);
+    server.add_handler("GET", "/api/events", move |request: &Request| {
+        let params = Params::from_form(request.query());
+
+        if let Err(err) = session_copy_6.lock().unwrap().auth(&params.login, &params.password) {
+            return Response::new(Status::Unauthorized).with_json(&json!({ "result": format!("{:?}", err) }));
+        }
+
+        let offset = request.header("Last-Event-ID")
+            .and_then(|e| e.trim().parse::<usize>().ok())
+            .map(|e| e.saturating_add(1))
+            .unwrap_or(0);
+
+        println!("i: user {} subscribed to events from {}", params.login, offset);
+
+        let session = Arc::clone(&session_copy_6);
+
+        Response::event_stream(move |stream| {
+            let mut session = session.lock().unwrap();
+
+            let valid_session = match session.auth(&params.login, &params.password) {
+                Ok(e) => e,
+                Err(_) => {
+                    let _ = stream.close();
+                    return;
+                },
+            };
+
+            // Missed messages and subscription are under the same lock, so nothing is lost between them
+            for message in valid_session.get_messages(offset) {
+                if stream.send(&message_event(&message)).is_err() {
+                    return;
+                }
             }
+
+            let handle = stream.clone();
+
+            valid_session.subscribe(move || handle.is_open(), move |message| stream.send(&message_event(message)).is_ok());
+        })
+    });
+}
+
+/// Add error handlers which send pages of `htdocs` (it's called again to reload pages).
+/// Pages are read once, because some error handlers are run by event loop, so they must not wait for disk.
+fn add_error_pages(server: &Server) {
+    let pages = vec![
+        (RequestError::NotFound, Status::NotFound),
+        (RequestError::MethodNotAllowed, Status::MethodNotAllowed),
+        (RequestError::BadRequest, Status::BadRequest),
+        (RequestError::RequestTimeout, Status::RequestTimeout),
+        (RequestError::PayloadTooLarge, Status::PayloadTooLarge),
+        (RequestError::RequestHeaderFieldsTooLarge, Status::RequestHeaderFieldsTooLarge),
+        (RequestError::ServiceUnavailable, Status::ServiceUnavailable),
+        (RequestError::InternalServerError, Status::InternalServerError),
+    ];
+
+    for (error, status) in pages {
+        // Default response is sent if page can't be read
+        let page = match fs::read(format!("htdocs/{}.html", status.code())) {
+            Ok(e) => e,
+            Err(e) => {
+                println!("e: can't read page of {:?}: {}", error, e);
+                continue;
+            },
         };
 
-        headers.push(String::from("Content-type: application/json; charset=utf-8"));
-        headers.push(format!("Content-length: {}", body.len()));
+        server.add_error_handler(error, move |_: &Request| {
+            Response::new(status)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(page.clone())
+        });
+    }
+}
 
-        (
-            headers,
-            body,
-        )
-    }));
-    
-    println!("Rust TalkBack Server");
-    println!("Press Enter to shutdown...");
-    stdin()
-        .read_line(&mut String::new())
-        .unwrap();
+/// Serve homepage at `/` and all files of `htdocs` under `/static` (browser checks them on every use).
+/// Files aren't mounted at `/`, so unknown paths get 404 instead of 405 of catch-all route.
+fn add_pages(server: &Server) {
+    server.add_handler("GET", "/", StaticFiles::new("/", "htdocs").with_cache_control("no-cache"));
+    server.add_handler("GET", "/static/*file", StaticFiles::new("/static", "htdocs").with_cache_control("no-cache"));
+}
+
+/// Command of main thread from terminal or signals
+enum Control {
+    Reload,
+    Shutdown,
 }
 
-fn params_from_body(body: &str) -> HashMap<String, String> {
-    body.split("&").map(|e| {
-        let e: Vec<&str> = e.split("=").collect();
+/// Translate SIGHUP to reload and SIGTERM or SIGINT to shutdown
+#[cfg(unix)]
+fn listen_signals(control_tx: Sender<Control>) {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
 
-        (
-            e.get(0).or(Some(&"")).unwrap().to_string(),
-            e.get(1).or(Some(&"")).unwrap().to_string(),
-        )
-    }).collect()
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM]).expect("Fail to listen signals!");
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let control = if signal == SIGHUP { Control::Reload } else { Control::Shutdown };
+
+            if control_tx.send(control).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Settings of server from `talkback.json` (missing ones have default values)
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Settings {
+    max_threads_number: Option<usize>,
+    max_body_size: Option<usize>,
+    /// Seconds
+    keep_alive_timeout: Option<u64>,
+    compression_threshold: Option<usize>,
+    hsts: Option<String>,
+}
+
+impl Settings {
+    /// Read settings file (default settings are used if it's missing or invalid)
+    fn load() -> Settings {
+        let contents = match fs::read_to_string(SETTINGS_FILE) {
+            Ok(e) => e,
+            Err(_) => return Settings::default(),
+        };
+
+        serde_json::from_str(&contents).unwrap_or_else(|error| {
+            println!("e: invalid settings file: {}", error);
+            Settings::default()
+        })
+    }
+
+    fn config(self) -> Config {
+        let default = Config::default();
+
+        Config {
+            max_threads_number: self.max_threads_number.unwrap_or(default.max_threads_number),
+            max_body_size: self.max_body_size.unwrap_or(default.max_body_size),
+            keep_alive_timeout: self.keep_alive_timeout.map(Duration::from_secs).unwrap_or(default.keep_alive_timeout),
+            compression_threshold: self.compression_threshold.unwrap_or(default.compression_threshold),
+            hsts: self.hsts,
+            ..default
+        }
+    }
+}
+
+/// Certificate and key from files of `TALKBACK_CERT` and `TALKBACK_KEY` (HTTPS is served if they are set)
+#[cfg(feature = "tls")]
+fn certificates() -> Option<Arc<Certificates>> {
+    let (cert_path, key_path) = match (env::var("TALKBACK_CERT"), env::var("TALKBACK_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+        _ => return None,
+    };
+
+    let certificates = Certificates::new();
+
+    if let Err(error) = certificates.add(None, &cert_path, &key_path) {
+        panic!("Fail to load certificate: {}", error);
+    }
+
+    Some(Arc::new(certificates))
+}
+
+/// Event of chat message with its id (it's sent back in `Last-Event-ID`)
+fn message_event(message: &Message) -> ServerEvent {
+    let json = serde_json::to_string(message).unwrap_or_default();
+
+    ServerEvent::new(&json)
+        .with_id(&message.id().to_string())
+        .with_event("message")
+}
+
+/// WebSocket connection of user which gets new messages and sends user's ones
+struct ChatSocket {
+    session: Arc<Mutex<AnonymSession>>,
+    login: String,
+    password: String,
+}
+
+impl WebSocketHandler for ChatSocket {
+    fn on_open(&self, socket: &WebSocket) {
+        let mut session = self.session.lock().unwrap();
+
+        if let Ok(valid_session) = session.auth(&self.login, &self.password) {
+            let handle = socket.clone();
+            let socket = socket.clone();
+
+            // Subscriber is removed when connection is closed
+            valid_session.subscribe(move || handle.is_open(), move |message| match serde_json::to_string(message) {
+                Ok(json) => socket.send_text(&json).is_ok(),
+                Err(_) => socket.is_open(),
+            });
+        }
+    }
+
+    fn on_message(&self, _: &WebSocket, message: WebSocketMessage) {
+        let text = match message {
+            WebSocketMessage::Text(e) => e,
+            WebSocketMessage::Binary(_) => return,
+        };
+
+        let mut session = self.session.lock().unwrap();
+
+        if let Ok(valid_session) = session.auth(&self.login, &self.password) {
+            let message = valid_session.add_message(&self.login, &text);
+
+            println!("i: message was sent: {}", message.format());
+        }
+    }
+}
+
+/// Params of API request
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Params {
+    login: String,
+    password: String,
+    message: String,
+}
+
+impl Params {
+    /// Read params from JSON or urlencoded body (depends on `Content-Type`)
+    fn from_body(request: &Request) -> Result<Params, Response> {
+        let json = request.header("Content-Type")
+            .map(|e| e.to_ascii_lowercase().starts_with("application/json"))
+            .unwrap_or(false);
+
+        if !json {
+            return Ok(Params::from_form(&request.form()));
+        }
+
+        request.json().map_err(|error| {
+            println!("e: invalid JSON body: {}", error);
+
+            Response::new(Status::BadRequest).with_json(&json!({ "result": "Invalid JSON!" }))
+        })
+    }
+
+    fn from_form(form: &Form) -> Params {
+        let value = |key| form.get(key).unwrap_or("").to_string();
+
+        Params {
+            login: value("login"),
+            password: value("password"),
+            message: value("message"),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::{self, File}, path::Path, time::Duration, net::TcpStream, io::{Write, Read}, thread};
-    use crate::{sessions::{AnonymSession, SessionError}, server::{Server, RequestError}};
+    use std::{fs::{self, File}, path::Path, time::{Duration, Instant}, net::TcpStream, io::{Write, Read, self}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use talkback::server::{Config, Handler, Next, Redirect, ShutdownReport, Request, RequestError, Response, RouteOptions, Server, ServerEvent, StaticFiles, Status, WebSocket, WebSocketHandler, WebSocketMessage};
+    use crate::sessions::{AnonymSession, SessionError};
 
     #[test]
     fn new_session_with_user_and_message() {
@@ -297,6 +590,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::get_first)]
     fn message_offset() {
         let (login, password, message1, message2) = data();
 
@@ -311,10 +605,73 @@ mod tests {
         assert_eq!(valid_session.get_messages(1).len(), 1);
         assert_eq!(valid_session.get_messages(2).len(), 0);
 
-        assert_eq!(valid_session.get_messages(1).get(0).unwrap().format(), format!("{}: {}", login, message2));
+        assert_eq!(valid_session.get_messages(1).get(0).unwrap().format(), format!("<b>{}</b>: {}", login, message2));
+    }
+
+    #[test]
+    fn messages_json() {
+        let (login, password, _, _) = data();
+
+        let mut session = AnonymSession::new();
+
+        let valid_session = session.register(&login, &password).unwrap();
+
+        valid_session.add_message(&login, "Quote \" and backslash \\ </b>");
+        valid_session.add_message(&login, "Привет!");
+
+        let messages = serde_json::to_value(valid_session.get_messages(0)).unwrap();
+
+        assert_eq!(messages[0]["id"], 0);
+        assert_eq!(messages[0]["author"], login.as_str());
+        assert_eq!(messages[0]["text"], "Quote \" and backslash \\ </b>");
+        assert!(messages[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(messages[1]["id"], 1);
+        assert_eq!(messages[1]["text"], "Привет!");
+    }
+
+    #[test]
+    fn message_subscribers() {
+        let (login, password, message1, message2) = data();
+
+        let mut session = AnonymSession::new();
+
+        let valid_session = session.register(&login, &password).unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_copy = Arc::clone(&received);
+
+        // Subscriber wants the only message
+        valid_session.subscribe(|| true, move |message| {
+            received_copy.lock().unwrap().push(message.format_text());
+            false
+        });
+
+        valid_session.add_message(&login, &message1);
+        valid_session.add_message(&login, &message2);
+
+        assert_eq!(*received.lock().unwrap(), vec![format!("{}: {}", login, message1)]);
+
+        // Closed subscribers are removed on the next subscribing with no messages
+        let open = Arc::new(AtomicBool::new(true));
+        let open_copy = Arc::clone(&open);
+        let closed = Arc::new(());
+        let closed_copy = Arc::clone(&closed);
+
+        valid_session.subscribe(move || open_copy.load(Ordering::SeqCst), move |_| {
+            let _ = &closed_copy;
+            true
+        });
+
+        assert_eq!(Arc::strong_count(&closed), 2);
+
+        open.store(false, Ordering::SeqCst);
+        valid_session.subscribe(|| true, |_| true);
+
+        assert_eq!(Arc::strong_count(&closed), 1);
     }
 
     #[test]
+    #[allow(clippy::match_like_matches_macro)]
     fn register_error() {
         let (login, password, _, _) = data();
 
@@ -348,6 +705,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::match_like_matches_macro)]
     fn auth_error() {
         let (login, password, _, _) = data();
 
@@ -416,106 +774,55 @@ mod tests {
     fn start_server() {
         let server = Server::new("0.0.0.0:80", 2);
 
-        server.add_error_handler(RequestError::NotFound, Box::new(|_, _, _| {
-            let mut headers = Vec::new();
-            let content = fs::read_to_string("htdocs/404.html").unwrap();
-
-            headers.push(String::from("HTTP/1.1 404 Not Found"));
-            headers.push(String::from("Content-type: text/html; charset=utf-8"));
-            headers.push(format!("Content-length: {}", content.len()));
-
-            (
-                headers,
-                content,
-            )
-        }));
-
-        server.add_error_handler(RequestError::BadRequest, Box::new(|_, _, _| {
-            let mut headers = Vec::new();
-            let content = fs::read_to_string("htdocs/400.html").unwrap();
-
-            headers.push(String::from("HTTP/1.1 400 Bad Request"));
-            headers.push(String::from("Content-type: text/html; charset=utf-8"));
-            headers.push(format!("Content-length: {}", content.len()));
-
-            (
-                headers,
-                content,
-            )
-        }));
-
-        server.add_error_handler(RequestError::ServiceUnavailable, Box::new(|_, _, _| {
-            let mut headers = Vec::new();
-            let content = fs::read_to_string("htdocs/503.html").unwrap();
-
-            headers.push(String::from("HTTP/1.1 503 Service Unavailable"));
-            headers.push(String::from("Content-type: text/html; charset=utf-8"));
-            headers.push(format!("Content-length: {}", content.len()));
-
-            (
-                headers,
-                content,
-            )
-        }));
-
-        server.add_handler("GET", "/hello.html", Box::new(|_, _, _| {
-            println!("hello endpoint");
+        server.add_error_handler(RequestError::NotFound, |_: &Request| {
+            Response::new(Status::NotFound)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(fs::read("htdocs/404.html").unwrap())
+        });
 
-            let mut headers = Vec::new();
-            let content = fs::read_to_string("htdocs/hello.html").unwrap();
+        server.add_error_handler(RequestError::BadRequest, |_: &Request| {
+            Response::new(Status::BadRequest)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(fs::read("htdocs/400.html").unwrap())
+        });
+
+        server.add_error_handler(RequestError::ServiceUnavailable, |_: &Request| {
+            Response::new(Status::ServiceUnavailable)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(fs::read("htdocs/503.html").unwrap())
+        });
 
-            headers.push(String::from("HTTP/1.1 200 OK"));
-            headers.push(String::from("Content-type: text/html; charset=utf-8"));
-            headers.push(format!("Content-length: {}", content.len()));
+        server.add_handler("GET", "/hello.html", |_: &Request| {
+            println!("hello endpoint");
 
-            (
-                headers,
-                content,
-            )
-        }));
+            Response::new(Status::Ok)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(fs::read("htdocs/hello.html").unwrap())
+        });
     
-        server.add_handler("GET", "/highload.html", Box::new(|_, _, _| {
+        server.add_handler("GET", "/highload.html", |_: &Request| {
             println!("highload endpoint");
             thread::sleep(Duration::from_secs(10));
-            
-            let mut headers = Vec::new();
-            let content = String::from("DONE!");
-
-            headers.push(String::from("HTTP/1.1 200 OK"));
-            headers.push(String::from("Content-type: text/html; charset=utf-8"));
-            headers.push(format!("Content-length: {}", content.len()));
-
-            (
-                headers,
-                content,
-            )
-        }));
+
+            Response::new(Status::Ok)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body("DONE!")
+        });
         
         thread::sleep(Duration::from_secs(5));
 
         if let Ok(mut stream) = TcpStream::connect("localhost:80") {
             // hello endpoint
-            stream.write_all(b"GET /hello.html HTTP/1.1").unwrap();
-
-            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
-
-            let mut buffer = [0; 1024];
-            stream.read(&mut buffer).unwrap();
-
-            assert_ne!(std::str::from_utf8(&buffer).unwrap().find("Welcome"), None);
+            stream.write_all(b"GET /hello.html HTTP/1.1\r\n\r\n").unwrap();
 
+            assert_ne!(read_response(&mut stream, 5).find("Welcome"), None);
         }
 
         if let Ok(mut stream) = TcpStream::connect("localhost:80") {
             // 404 endpoint
-            stream.write_all(b"GET /asdasd.html HTTP/1.1").unwrap();
-
-            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
-
-            let mut buffer = [0; 1024];
-            stream.read(&mut buffer).unwrap();
+            stream.write_all(b"GET /asdasd.html HTTP/1.1\r\n\r\n").unwrap();
 
-            assert_ne!(std::str::from_utf8(&buffer).unwrap().find("Not Found"), None);
+            assert_ne!(read_response(&mut stream, 5).find("Not Found"), None);
         }
 
         for _ in 0..2 {
@@ -523,14 +830,9 @@ mod tests {
                 thread::sleep(Duration::from_secs(1));
 
                 if let Ok(mut stream) = TcpStream::connect("localhost:80") {
-                    stream.write_all(b"GET /highload.html HTTP/1.1").unwrap();
-
-                    stream.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
-
-                    let mut buffer = [0; 1024];
-                    stream.read(&mut buffer).unwrap();
+                    stream.write_all(b"GET /highload.html HTTP/1.1\r\n\r\n").unwrap();
 
-                    assert_ne!(std::str::from_utf8(&buffer).unwrap().find("DONE"), None);
+                    assert_ne!(read_response(&mut stream, 15).find("DONE"), None);
                 }
             });
         }
@@ -538,14 +840,2300 @@ mod tests {
         thread::sleep(Duration::from_secs(4));
 
         if let Ok(mut stream) = TcpStream::connect("localhost:80") {
-            stream.write_all(b"GET /highload.html HTTP/1.1").unwrap();
+            stream.write_all(b"GET /highload.html HTTP/1.1\r\n\r\n").unwrap();
 
-            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_ne!(read_response(&mut stream, 5).find("Service Unavailable"), None);
+        }
+    }
+
+    #[test]
+    fn long_request_body() {
+        let server = Server::with_config("0.0.0.0:8081", Config {
+            max_body_size: 16 * 1024,
+            ..Config::default()
+        });
+
+        server.add_handler("POST", "/echo", |request: &Request| {
+            Response::new(Status::Ok).with_body(request.body())
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let message = "Привет, мир! ".repeat(500);
+
+        let mut stream = TcpStream::connect("localhost:8081").unwrap();
+        let request = format!("POST /echo HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+            "X-Padding: 0123456789abcdef0123456789abcdef\r\n".repeat(40),
+            message.len(),
+            message
+        );
+
+        // Send request in small parts to split it in the middle of utf-8 characters
+        for part in request.as_bytes().chunks(777) {
+            stream.write_all(part).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(&message));
+
+        let mut stream = TcpStream::connect("localhost:8081").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 16385\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 413 Payload Too Large"));
 
-            let mut buffer = [0; 1024];
-            stream.read(&mut buffer).unwrap();
+        // Length with sign is invalid
+        let mut stream = TcpStream::connect("localhost:8081").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello").unwrap();
 
-            assert_ne!(std::str::from_utf8(&buffer).unwrap().find("Service Unavailable"), None);
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[test]
+    fn chunked_transfer_encoding() {
+        let server = Server::new("0.0.0.0:8082", 2);
+
+        server.add_handler("POST", "/echo", |request: &Request| {
+            Response::new(Status::Ok)
+                .with_header("Transfer-Encoding", "chunked")
+                .with_header("X-Checksum", request.header("checksum").unwrap_or(""))
+                .with_body(request.body().repeat(2000))
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        stream.write_all(b"6;name=value\r\nHello,\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"7\r\n world!\r\n0\r\nChecksum: 42\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("X-Checksum: 42"));
+
+        // Decode chunked body
+        let mut decoded = String::new();
+        let mut rest = body;
+
+        loop {
+            let (size, tail) = rest.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+
+            if size == 0 {
+                break;
+            }
+
+            decoded.push_str(&tail[..size]);
+            rest = &tail[size + 2..];
         }
+
+        assert_eq!(decoded, "Hello, world!".repeat(2000));
+
+        // Huge chunk size doesn't overflow and server keeps working
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 413 Payload Too Large"));
+
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n10000000000000000\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 400 Bad Request"));
+
+        // Size with sign is invalid
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+2\r\nOK\r\n0\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 400 Bad Request"));
+
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nOK\r\n0\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 200 OK"));
+
+        // Limit which is lowered by reload below decoded part of body is checked with no overflow
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(format!("POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n50\r\n{}\r\n", "x".repeat(0x50)).as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        server.reload(Config {
+            max_body_size: 10,
+            ..Config::default()
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        stream.write_all(b"1\r\nx\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 413 Payload Too Large"));
+
+        let mut stream = TcpStream::connect("localhost:8082").unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nOK\r\n0\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn keep_alive_and_pipelining() {
+        let server = Server::with_config("0.0.0.0:8083", Config {
+            max_requests_per_connection: 3,
+            ..Config::default()
+        });
+
+        server.add_handler("GET", "/echo", |request: &Request| {
+            Response::new(Status::Ok).with_body(request.query().get("n").unwrap_or("").to_string())
+        });
+
+        server.add_handler("GET", "/slow", |_: &Request| {
+            thread::sleep(Duration::from_secs(1));
+
+            Response::new(Status::Ok).with_body("slow")
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        // Pipelined requests are answered in order on the same connection
+        let mut stream = TcpStream::connect("localhost:8083").unwrap();
+        stream.write_all(b"GET /echo?n=first HTTP/1.1\r\n\r\nGET /echo?n=second HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.contains("Connection: keep-alive"));
+        assert!(response.ends_with("first"));
+        assert!(read_response(&mut stream, 5).ends_with("second"));
+
+        // The last allowed request closes connection
+        stream.write_all(b"GET /echo?n=third HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("third"));
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // Client can close connection by itself
+        let mut stream = TcpStream::connect("localhost:8083").unwrap();
+        stream.write_all(b"GET /echo?n=last HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).ends_with("last"));
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // HTTP/1.0 connections are closed by default
+        let mut stream = TcpStream::connect("localhost:8083").unwrap();
+        stream.write_all(b"GET /echo?n=old HTTP/1.0\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).contains("Connection: close"));
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // Requests which are pipelined behind slow one are read when it's answered (bodies are bigger than read buffer)
+        let body = "x".repeat(700 * 1024);
+        let mut stream = TcpStream::connect("localhost:8083").unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        stream.write_all(format!("GET /echo?n=a HTTP/1.1\r\nContent-Length: {0}\r\n\r\n{1}GET /echo?n=b HTTP/1.1\r\nContent-Length: {0}\r\n\r\n{1}", body.len(), body).as_bytes()).unwrap();
+
+        assert!(read_response(&mut stream, 5).ends_with("slow"));
+        assert!(read_response(&mut stream, 5).ends_with("a"));
+        assert!(read_response(&mut stream, 5).ends_with("b"));
+
+        // Server doesn't read unlimited data while request is processed, so client is blocked
+        let mut stream = TcpStream::connect("localhost:8083").unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+
+        let sent = Arc::new(AtomicUsize::new(0));
+        let mut writer = stream.try_clone().unwrap();
+
+        {
+            let sent = Arc::clone(&sent);
+
+            thread::spawn(move || {
+                while writer.write_all(&[b'x'; 64 * 1024]).is_ok() {
+                    sent.fetch_add(64 * 1024, Ordering::SeqCst);
+                }
+            });
+        }
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(sent.load(Ordering::SeqCst) < 32 * 1024 * 1024);
+        assert!(read_response(&mut stream, 5).ends_with("slow"));
+    }
+
+    #[test]
+    fn worker_pool_queue() {
+        let server = Server::with_config("0.0.0.0:8084", Config {
+            max_threads_number: 1,
+            queue_size: 1,
+            queue_timeout: Duration::from_secs(1),
+            ..Config::default()
+        });
+
+        server.add_handler("GET", "/slow", |_: &Request| {
+            thread::sleep(Duration::from_secs(3));
+
+            Response::new(Status::Ok).with_body("DONE!")
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        // The only worker is busy
+        let mut busy = TcpStream::connect("localhost:8084").unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        // Request waits in queue until timeout expires
+        let started = Instant::now();
+        let mut queued = TcpStream::connect("localhost:8084").unwrap();
+        queued.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        // Queue is full, so request is rejected immediately
+        let mut rejected = TcpStream::connect("localhost:8084").unwrap();
+        rejected.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut rejected, 5).starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Idle connections don't occupy worker threads and queue
+        let mut idle = Vec::new();
+
+        for _ in 0..100 {
+            idle.push(TcpStream::connect("localhost:8084").unwrap());
+        }
+
+        assert!(read_response(&mut queued, 5).starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        assert!(read_response(&mut busy, 5).ends_with("DONE!"));
+    }
+
+    #[test]
+    fn many_concurrent_connections() {
+        let server = Server::with_config("0.0.0.0:8085", Config {
+            max_threads_number: 2,
+            queue_size: 300,
+            ..Config::default()
+        });
+
+        server.add_handler("GET", "/hello", |_: &Request| {
+            Response::new(Status::Ok).with_body("Hello!")
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        // Open connections are served by two worker threads only
+        let mut streams = (0..300)
+            .map(|_| TcpStream::connect("localhost:8085").unwrap())
+            .collect::<Vec<TcpStream>>();
+
+        for _ in 0..2 {
+            for stream in streams.iter_mut() {
+                stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+            }
+
+            for stream in streams.iter_mut() {
+                assert!(read_response(stream, 5).ends_with("Hello!"));
+            }
+        }
+    }
+
+    #[test]
+    fn slow_reader() {
+        /// Endless body which counts bytes read by server
+        struct CountingReader(Arc<AtomicUsize>);
+
+        impl Read for CountingReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                buf.fill(b'x');
+                self.0.fetch_add(buf.len(), Ordering::SeqCst);
+                Ok(buf.len())
+            }
+        }
+
+        let server = Server::new("0.0.0.0:8110", 2);
+        let read = Arc::new(AtomicUsize::new(0));
+
+        {
+            let read = Arc::clone(&read);
+
+            server.add_handler("GET", "/endless", move |_: &Request| {
+                Response::new(Status::Ok).with_stream(CountingReader(Arc::clone(&read)))
+            });
+        }
+
+        server.add_handler("GET", "/hello", |_: &Request| Response::new(Status::Ok).with_body("Hello!"));
+
+        thread::sleep(Duration::from_secs(1));
+
+        // Client which doesn't read response gets only a few parts of it
+        let mut stream = TcpStream::connect("localhost:8110").unwrap();
+        stream.write_all(b"GET /endless HTTP/1.1\r\n\r\n").unwrap();
+
+        thread::sleep(Duration::from_secs(1));
+        assert!(read.load(Ordering::SeqCst) < 32 * 1024 * 1024);
+
+        // Body isn't read after connection is closed, so worker thread is free again
+        drop(stream);
+        thread::sleep(Duration::from_millis(500));
+
+        let total = read.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(read.load(Ordering::SeqCst), total);
+
+        let mut stream = TcpStream::connect("localhost:8110").unwrap();
+        stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).ends_with("Hello!"));
+    }
+
+    #[test]
+    fn route_patterns() {
+        let server = Server::new("0.0.0.0:8086", 2);
+
+        let routes = [
+            ("GET", "/api/rooms/:id/messages", "messages"),
+            ("POST", "/api/rooms/:id/messages", "post"),
+            ("GET", "/users/:id", "user"),
+            ("GET", "/users/me", "me"),
+            ("GET", "/static/*file", "static"),
+            ("GET", "/static/css/*file", "css"),
+        ];
+
+        for &(method, path, name) in routes.iter() {
+            server.add_handler(method, path, move |request: &Request| {
+                let mut params = request.params().iter()
+                    .chain(request.query().iter().map(|(key, value)| (key, value)))
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<String>>();
+                params.sort();
+
+                Response::new(Status::Ok).with_body(format!("{} {}", name, params.join(",")))
+            });
+        }
+
+        thread::sleep(Duration::from_secs(1));
+
+        let cases = [
+            ("GET /api/rooms/42/messages?offset=3", "messages id=42,offset=3"),
+            ("POST /api/rooms/7/messages", "post id=7"),
+            ("GET /users/15", "user id=15"),
+            ("GET /users/me", "me "),
+            ("GET /static/img/logo.png", "static file=img/logo.png"),
+            ("GET /static/css/main.css", "css file=main.css"),
+            ("GET /static/", "static file="),
+            // Values are decoded after matching
+            ("GET /users/a%20b", "user id=a b"),
+            ("GET /users/a%2Fb", "user id=a/b"),
+            ("GET /users/%D0%AF", "user id=Я"),
+        ];
+
+        let mut stream = TcpStream::connect("localhost:8086").unwrap();
+
+        for (request, expected) in cases.iter() {
+            stream.write_all(format!("{} HTTP/1.1\r\n\r\n", request).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.ends_with(expected), "{} -> {}", request, response);
+        }
+
+        for request in ["GET /api/rooms//messages", "GET /api/rooms/42", "GET /users/15/avatar"].iter() {
+            stream.write_all(format!("{} HTTP/1.1\r\n\r\n", request).as_bytes()).unwrap();
+
+            assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 404 Not Found"), "{}", request);
+        }
+
+        // Value which isn't valid utf-8
+        stream.write_all(b"GET /users/%FF HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let server = Server::new("0.0.0.0:8087", 2);
+
+        server.add_error_handler(RequestError::MethodNotAllowed, |_: &Request| {
+            Response::new(Status::MethodNotAllowed)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(fs::read("htdocs/405.html").unwrap())
+        });
+
+        server.add_handler("POST", "/api/message", |_: &Request| {
+            Response::new(Status::Created)
+        });
+
+        // Pages are served like in main
+        crate::add_pages(&server);
+
+        server.add_handler("GET", "/hello.html", |_: &Request| {
+            Response::new(Status::Ok)
+                .with_header("Content-type", "text/html; charset=utf-8")
+                .with_body(fs::read("htdocs/hello.html").unwrap())
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8087").unwrap();
+
+        // Path exists, but method is wrong
+        stream.write_all(b"GET /api/message HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"));
+        assert!(response.contains("Allow: OPTIONS, POST\r\n"));
+        assert!(response.contains("<h1>405 Method Not Allowed</h1>"));
+
+        // Allowed methods
+        stream.write_all(b"OPTIONS /hello.html HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 204 No Content"));
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+
+        // Unknown path is still 404
+        stream.write_all(b"DELETE /unknown HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 404 Not Found"));
+
+        stream.write_all(b"POST /unknown HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 404 Not Found"));
+
+        // Homepage
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("Cache-Control: no-cache\r\n"));
+
+        // Other files of `htdocs` are served under prefix
+        stream.write_all(b"GET /static/404.html HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Cache-Control: no-cache\r\n"));
+
+        stream.write_all(b"GET /static/missing.png HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 404 Not Found"));
+
+        // HEAD is served by GET's handler with no body
+        stream.write_all(b"HEAD /hello.html HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let length = fs::read_to_string("htdocs/hello.html").unwrap().len();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&format!("Content-Length: {}\r\n", length)));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn typed_handlers() {
+        /// Handler with its own state
+        struct Greeter {
+            greeting: String,
+        }
+
+        impl Handler for Greeter {
+            fn handle(&self, request: &Request) -> Response {
+                let body = format!("{}, {}! ({}, {})",
+                    self.greeting,
+                    request.param("name").unwrap_or(""),
+                    request.headers().get_all("x-client").join(", "),
+                    request.peer_addr().ip()
+                );
+
+                Response::new(Status::Ok)
+                    .with_header("Content-Type", "text/plain")
+                    .with_body(body)
+            }
+        }
+
+        let server = Server::new("0.0.0.0:8088", 2);
+
+        server.add_handler("GET", "/greet/:name", Greeter {
+            greeting: String::from("Hello"),
+        });
+
+        server.add_handler("GET", "/stream", |_: &Request| {
+            // Stream of unknown length is sent by chunks
+            Response::new(Status::Ok).with_stream(io::repeat(b'x').take(200 * 1024))
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8088").unwrap();
+
+        // Header names are case-insensitive and repeated headers are kept
+        stream.write_all(b"GET /greet/world HTTP/1.1\r\nX-Client: one\r\nx-client: two\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        assert!(response.ends_with("Hello, world! (one, two, 127.0.0.1)"));
+
+        stream.write_all(b"GET /stream HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert_eq!(body.matches('x').count(), 200 * 1024);
+
+        // HTTP/1.0 client doesn't understand chunks, so connection is closed after body
+        stream.write_all(b"GET /stream HTTP/1.0\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Connection: close"));
+        assert_eq!(body, "x".repeat(200 * 1024));
+    }
+
+    #[test]
+    fn header_parsing() {
+        let server = Server::new("0.0.0.0:8089", 2);
+
+        server.add_handler("GET", "/headers", |request: &Request| {
+            let headers = request.headers();
+
+            let body = format!("{}|{}|{}|{}",
+                headers.get_all("Cookie").join(";"),
+                headers.get("accept").unwrap_or(""),
+                headers.get_all("ACCEPT").len(),
+                headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>().join(",")
+            );
+
+            Response::new(Status::Ok).with_body(body)
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8089").unwrap();
+        stream.write_all(b"GET /headers HTTP/1.1\r\nCookie: a=1\r\nAccept: text/html\r\ncookie:b=2\r\naccept: */*  \r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.ends_with("a=1;b=2|text/html|2|Cookie,Accept,cookie,accept"), "{}", response);
+
+        let invalid = [
+            // Obsolete line folding
+            "GET /headers HTTP/1.1\r\nAccept: text/html,\r\n text/plain\r\n\r\n",
+            // Whitespace between name and colon
+            "GET /headers HTTP/1.1\r\nAccept : text/html\r\n\r\n",
+            // No colon
+            "GET /headers HTTP/1.1\r\nAccept text/html\r\n\r\n",
+            // Empty name
+            "GET /headers HTTP/1.1\r\n: text/html\r\n\r\n",
+            // Different values of Content-Length
+            "GET /headers HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            // Request line with unknown version, with no version or with extra part
+            "GET /headers BOGUS\r\n\r\n",
+            "GET /headers HTTP/2.0\r\n\r\n",
+            "GET /headers\r\n\r\n",
+            "GET /headers HTTP/1.1 extra\r\n\r\n",
+        ];
+
+        for request in invalid.iter() {
+            let mut stream = TcpStream::connect("localhost:8089").unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{:?} -> {}", request, response);
+        }
+    }
+
+    #[test]
+    fn form_decoding() {
+        let server = Server::new("0.0.0.0:8090", 2);
+
+        let handler = |request: &Request| {
+            let form = if request.method() == "POST" { request.form() } else { request.query().clone() };
+
+            let body = form.iter()
+                .map(|(key, value)| format!("[{}]=[{}]", key, value))
+                .collect::<Vec<String>>()
+                .join(" ");
+
+            Response::new(Status::Ok).with_body(body)
+        };
+
+        server.add_handler("GET", "/form", handler);
+        server.add_handler("POST", "/form", handler);
+
+        thread::sleep(Duration::from_secs(1));
+
+        let expected = "[login]=[John Smith] [password]=[a=b&c] [tag]=[one] [tag]=[two] [text]=[Привет, мир!] [broken]=[100%] [flag]=[]";
+        let data = "login=John+Smith&password=a%3Db%26c&tag=one&tag=two&&text=%D0%9F%D1%80%D0%B8%D0%B2%D0%B5%D1%82%2C%20%D0%BC%D0%B8%D1%80!&broken=100%&flag";
+
+        let mut stream = TcpStream::connect("localhost:8090").unwrap();
+
+        // Query string
+        stream.write_all(format!("GET /form?{} HTTP/1.1\r\n\r\n", data).as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with(expected));
+
+        // Form body
+        stream.write_all(format!("POST /form HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}", data.len(), data).as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with(expected));
+
+        // Value with `=` isn't cut
+        stream.write_all(b"GET /form?password=abc==&x=%zz HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("[password]=[abc==] [x]=[%zz]"));
+    }
+
+    #[test]
+    fn json_bodies() {
+        #[derive(Deserialize)]
+        struct Note {
+            author: String,
+            text: String,
+        }
+
+        let server = Server::new("0.0.0.0:8091", 2);
+
+        server.add_handler("POST", "/notes", |request: &Request| {
+            match request.json::<Note>() {
+                Ok(note) => Response::new(Status::Created).with_json(&json!({
+                    "result": [{ "author": note.author, "text": note.text }],
+                })),
+                Err(_) => Response::new(Status::BadRequest).with_json(&json!({ "result": "Invalid JSON!" })),
+            }
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8091").unwrap();
+
+        let body = r#"{"author":"John","text":"Say \"hi\" \\ <b>"}"#;
+        stream.write_all(format!("POST /notes HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+
+        let response = read_response(&mut stream, 5);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 201 Created"));
+        assert!(head.contains("Content-Type: application/json; charset=utf-8"));
+
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["result"][0]["author"], "John");
+        assert_eq!(body["result"][0]["text"], "Say \"hi\" \\ <b>");
+
+        stream.write_all(b"POST /notes HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 7\r\n\r\n{\"bad\":").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.ends_with(r#"{"result":"Invalid JSON!"}"#));
+    }
+
+    #[test]
+    fn static_files() {
+        // Root with files and secret file outside of it
+        let base = std::env::temp_dir().join(format!("talkback_static_{}", std::process::id()));
+        let root = base.join("public");
+        let _ = fs::remove_dir_all(&base);
+
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>Home</h1>").unwrap();
+        fs::write(root.join("docs").join("index.html"), "<h1>Docs</h1>").unwrap();
+        fs::write(root.join("style.css"), "body {}").unwrap();
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0xff, 0x00, 0xfe]).unwrap();
+        fs::write(root.join("data.bin"), [0u8; 3]).unwrap();
+        fs::write(root.join("my file.txt"), "spaces").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+
+        let server = Server::new("0.0.0.0:8092", 2);
+
+        server.add_error_handler(RequestError::NotFound, |_: &Request| {
+            Response::new(Status::NotFound).with_body("Custom 404")
+        });
+
+        server.add_static("/static", &root);
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8092").unwrap();
+
+        let cases = [
+            ("/static/", "text/html; charset=utf-8", "<h1>Home</h1>"),
+            ("/static/docs/", "text/html; charset=utf-8", "<h1>Docs</h1>"),
+            ("/static/style.css", "text/css; charset=utf-8", "body {}"),
+            ("/static/my%20file.txt", "text/plain; charset=utf-8", "spaces"),
+        ];
+
+        for (path, content_type, body) in cases.iter() {
+            stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{} -> {}", path, response);
+            assert!(response.contains(&format!("Content-Type: {}\r\n", content_type)), "{}", path);
+            assert!(response.ends_with(body), "{}", path);
+        }
+
+        // Binary files are sent as is
+        stream.write_all(b"GET /static/logo.png HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response_bytes(&mut stream, 5);
+        assert!(contains_bytes(&response, b"Content-Type: image/png\r\n"));
+        assert!(response.ends_with(&[0x89, b'P', b'N', b'G', 0xff, 0x00, 0xfe]));
+
+        stream.write_all(b"GET /static/data.bin HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response_bytes(&mut stream, 5);
+        assert!(contains_bytes(&response, b"Content-Type: application/octet-stream\r\n"));
+
+        // Directory's path is redirected to the one with slash
+        stream.write_all(b"GET /static/docs HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently"));
+        assert!(response.contains("Location: /static/docs/\r\n"));
+
+        // Missing files and paths out of root get 404 of error handler
+        for path in ["/static/missing.html", "/static/../secret.txt", "/static/%2e%2e/secret.txt", "/static/docs/..%2f..%2fsecret.txt", "/static/link.txt"].iter() {
+            stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{} -> {}", path, response);
+            assert!(response.ends_with("Custom 404"), "{}", path);
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn conditional_get() {
+        let root = std::env::temp_dir().join(format!("talkback_cache_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("page.html"), "<h1>Page</h1>").unwrap();
+
+        let server = Server::new("0.0.0.0:8093", 2);
+
+        server.add_handler("GET", "/cached/*file", StaticFiles::new("/cached", &root).with_cache_control("public, max-age=60"));
+        server.add_static("/plain", &root);
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8093").unwrap();
+        stream.write_all(b"GET /cached/page.html HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        let header = |name: &str| response.lines()
+            .find_map(|e| e.strip_prefix(&format!("{}: ", name)))
+            .map(str::to_string);
+
+        let etag = header("ETag").unwrap();
+        let last_modified = header("Last-Modified").unwrap();
+        assert!(response.ends_with("<h1>Page</h1>"));
+        assert_eq!(header("Cache-Control").as_deref(), Some("public, max-age=60"));
+
+        // Unchanged file isn't sent again
+        let requests = [
+            format!("If-None-Match: {}", etag),
+            format!("If-None-Match: \"other\", W/{}", etag),
+            String::from("If-None-Match: *"),
+            format!("If-Modified-Since: {}", last_modified),
+        ];
+
+        for condition in requests.iter() {
+            stream.write_all(format!("GET /cached/page.html HTTP/1.1\r\n{}\r\n\r\n", condition).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 304 Not Modified"), "{} -> {}", condition, response);
+            assert!(response.contains(&format!("ETag: {}\r\n", etag)));
+            assert!(response.contains("Cache-Control: public, max-age=60\r\n"));
+            assert!(response.ends_with("\r\n\r\n"));
+        }
+
+        // Changed file or old date
+        let requests = [
+            String::from("If-None-Match: \"other\""),
+            String::from("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT"),
+            // If-None-Match wins over If-Modified-Since
+            format!("If-None-Match: \"other\"\r\nIf-Modified-Since: {}", last_modified),
+        ];
+
+        for condition in requests.iter() {
+            stream.write_all(format!("GET /cached/page.html HTTP/1.1\r\n{}\r\n\r\n", condition).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{} -> {}", condition, response);
+            assert!(response.ends_with("<h1>Page</h1>"));
+        }
+
+        // Modification of file changes its tag
+        thread::sleep(Duration::from_millis(20));
+        fs::write(root.join("page.html"), "<h1>New page</h1>").unwrap();
+
+        stream.write_all(format!("GET /cached/page.html HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag).as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("<h1>New page</h1>"));
+
+        // Cache-Control is set per route
+        stream.write_all(b"GET /plain/page.html HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.contains("ETag: "));
+        assert!(!response.contains("Cache-Control"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn range_requests() {
+        let root = std::env::temp_dir().join(format!("talkback_range_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("log.txt"), "0123456789abcdefghij").unwrap();
+
+        let server = Server::new("0.0.0.0:8094", 2);
+
+        server.add_handler("GET", "/export", |_: &Request| {
+            Response::new(Status::Ok)
+                .with_header("Content-Type", "text/plain")
+                .with_header("ETag", "\"v1\"")
+                .with_header("Accept-Ranges", "bytes")
+                .with_body("0123456789abcdefghij")
+        });
+
+        server.add_handler("GET", "/plain", |_: &Request| {
+            Response::new(Status::Ok).with_body("0123456789abcdefghij")
+        });
+
+        server.add_static("/files", &root);
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8094").unwrap();
+
+        let cases = [
+            ("/export", "bytes=0-4", "bytes 0-4/20", "01234"),
+            ("/export", "bytes=-5", "bytes 15-19/20", "fghij"),
+            ("/export", "bytes=15-", "bytes 15-19/20", "fghij"),
+            ("/export", "bytes=5-100", "bytes 5-19/20", "56789abcdefghij"),
+            // Overlapping ranges are merged
+            ("/export", "bytes=0-4, 3-8", "bytes 0-8/20", "012345678"),
+            ("/files/log.txt", "bytes=2-4", "bytes 2-4/20", "234"),
+        ];
+
+        for (path, range, content_range, body) in cases.iter() {
+            stream.write_all(format!("GET {} HTTP/1.1\r\nRange: {}\r\n\r\n", path, range).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 206 Partial Content"), "{} -> {}", range, response);
+            assert!(response.contains(&format!("Content-Range: {}\r\n", content_range)), "{}", range);
+            assert!(response.ends_with(&format!("\r\n\r\n{}", body)), "{} -> {}", range, response);
+        }
+
+        // Several ranges are sent as multipart body
+        stream.write_all(b"GET /files/log.txt HTTP/1.1\r\nRange: bytes=10-11,0-1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        let boundary = response.lines()
+            .find_map(|e| e.strip_prefix("Content-Type: multipart/byteranges; boundary="))
+            .unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content"));
+        assert!(response.ends_with(&format!(
+            "\r\n\r\n\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/20\r\n\r\n01\
+             \r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 10-11/20\r\n\r\nab\
+             \r\n--{0}--\r\n",
+            boundary
+        )));
+
+        // Unsatisfiable range
+        stream.write_all(b"GET /export HTTP/1.1\r\nRange: bytes=30-40\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable"));
+        assert!(response.contains("Content-Range: bytes */20\r\n"));
+
+        // The whole body is sent for invalid range, changed version or handler with no ranges support
+        let requests = [
+            "GET /export HTTP/1.1\r\nRange: bytes=abc\r\n\r\n",
+            "GET /export HTTP/1.1\r\nRange: bytes=5-1\r\n\r\n",
+            "GET /export HTTP/1.1\r\nRange: bytes=0-4\r\nIf-Range: \"v0\"\r\n\r\n",
+            "GET /plain HTTP/1.1\r\nRange: bytes=0-4\r\n\r\n",
+        ];
+
+        for request in requests.iter() {
+            stream.write_all(request.as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{:?} -> {}", request, response);
+            assert!(response.ends_with("\r\n\r\n0123456789abcdefghij"));
+        }
+
+        // Range is sent if version is the same
+        stream.write_all(b"GET /export HTTP/1.1\r\nRange: bytes=0-4\r\nIf-Range: \"v1\"\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("\r\n\r\n01234"));
+
+        stream.write_all(b"GET /files/log.txt HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream, 5);
+        let last_modified = response.lines().find_map(|e| e.strip_prefix("Last-Modified: ")).unwrap();
+        assert!(response.contains("Accept-Ranges: bytes\r\n"));
+
+        stream.write_all(format!("GET /files/log.txt HTTP/1.1\r\nRange: bytes=0-4\r\nIf-Range: {}\r\n\r\n", last_modified).as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("\r\n\r\n01234"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn compression() {
+        let text = "Hello, compression! ".repeat(500);
+
+        let server = Server::new("0.0.0.0:8095", 2);
+
+        for &(path, content_type, compress) in [("/text", "text/plain", true), ("/raw", "text/plain", false), ("/image", "image/png", true)].iter() {
+            let text = text.clone();
+
+            server.add_handler_with("GET", path, move |_: &Request| {
+                Response::new(Status::Ok)
+                    .with_header("Content-Type", content_type)
+                    .with_header("ETag", "\"v1\"")
+                    .with_body(text.clone())
+            }, RouteOptions {
+                compress,
+            });
+        }
+
+        server.add_handler("GET", "/small", |_: &Request| {
+            Response::new(Status::Ok).with_header("Content-Type", "text/plain").with_body("small")
+        });
+
+        let stream_text = text.clone();
+        server.add_handler("GET", "/stream", move |_: &Request| {
+            Response::new(Status::Ok)
+                .with_header("Content-Type", "application/json")
+                .with_stream(io::Cursor::new(stream_text.clone()))
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect("localhost:8095").unwrap();
+
+        let request = |stream: &mut TcpStream, path: &str, accept: &str| {
+            stream.write_all(format!("GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", path, accept).as_bytes()).unwrap();
+
+            let response = read_response_bytes(stream, 5);
+            let position = response.windows(4).position(|e| e == b"\r\n\r\n").unwrap();
+            let head = String::from_utf8(response[..position].to_vec()).unwrap();
+
+            (head, response[position + 4..].to_vec())
+        };
+
+        let cases = [
+            ("gzip", "gzip"),
+            ("deflate", "deflate"),
+            ("gzip;q=0, deflate", "deflate"),
+            ("gzip;q=0.5, deflate;q=0.8", "deflate"),
+            ("*;q=0.1, gzip", "gzip"),
+        ];
+
+        for (accept, coding) in cases.iter() {
+            let (head, body) = request(&mut stream, "/text", accept);
+
+            let mut decoded = String::new();
+
+            match *coding {
+                "gzip" => GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap(),
+                _ => ZlibDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap(),
+            };
+
+            assert!(head.contains(&format!("Content-Encoding: {}\r\n", coding)), "{} -> {}", accept, head);
+            assert!(head.contains("Vary: Accept-Encoding\r\n"));
+            assert!(head.contains("ETag: W/\"v1\"\r\n"));
+            assert!(body.len() < text.len());
+            assert_eq!(decoded, text);
+        }
+
+        // Identity body is sent if client doesn't accept codings, but response still varies
+        for accept in ["identity", "gzip;q=0, deflate;q=0"].iter() {
+            let (head, body) = request(&mut stream, "/text", accept);
+
+            assert!(!head.contains("Content-Encoding"), "{}", accept);
+            assert!(head.contains("Vary: Accept-Encoding\r\n"));
+            assert_eq!(body, text.as_bytes());
+        }
+
+        // Route with no compression, binary type and small body
+        for path in ["/raw", "/image", "/small"].iter() {
+            let (head, _) = request(&mut stream, path, "gzip");
+
+            assert!(!head.contains("Content-Encoding"), "{}", path);
+            assert!(!head.contains("Vary"), "{}", path);
+        }
+
+        // Stream is compressed by chunks
+        let (head, body) = request(&mut stream, "/stream", "gzip");
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+
+        let mut compressed = Vec::new();
+        let mut rest = &body[..];
+
+        loop {
+            let line_end = rest.windows(2).position(|e| e == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+
+            if size == 0 {
+                break;
+            }
+
+            compressed.extend_from_slice(&rest[line_end + 2..line_end + 2 + size]);
+            rest = &rest[line_end + 2 + size + 2..];
+        }
+
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls() {
+        use std::{convert::TryInto, sync::Arc};
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use rustls::pki_types::CertificateDer;
+        use talkback::server::Certificates;
+
+        let dir = std::env::temp_dir().join("talkback_tls");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // Self-signed certificates: default one and one for SNI name
+        let generate = |name: &str, file: &str| -> CertificateDer<'static> {
+            let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+            fs::write(dir.join(format!("{}.pem", file)), certified.cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", file)), certified.key_pair.serialize_pem()).unwrap();
+
+            certified.cert.der().clone()
+        };
+
+        let default_cert = generate("localhost", "default");
+        let named_cert = generate("chat.test", "named");
+
+        let certificates = Arc::new(Certificates::new());
+        certificates.add(None, dir.join("default.pem"), dir.join("default.key")).unwrap();
+        certificates.add(Some("chat.test"), dir.join("named.pem"), dir.join("named.key")).unwrap();
+
+        // Invalid files are reported
+        assert!(certificates.add(Some("other.test"), dir.join("missing.pem"), dir.join("default.key")).is_err());
+        assert!(certificates.add(Some("other.test"), dir.join("default.key"), dir.join("default.key")).is_err());
+        assert!(certificates.add(Some("other.test"), dir.join("default.pem"), dir.join("named.key")).is_err());
+
+        let server = Server::with_config("0.0.0.0:8096", Config {
+            tls: Some(Arc::clone(&certificates)),
+            hsts: Some(String::from("max-age=31536000")),
+            ..Config::default()
+        });
+
+        server.add_handler("GET", "/hello", |request: &Request| {
+            Response::new(Status::Ok).with_body(format!("Hello, {}!", request.path()))
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        // Connect with name and return certificate of server and responses (the last request closes connection)
+        let connect = |name: &str, trusted: &CertificateDer<'static>, last: &str| {
+            let mut roots = RootCertStore::empty();
+            roots.add(trusted.clone()).unwrap();
+
+            let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            let connection = ClientConnection::new(Arc::new(config), name.to_string().try_into().unwrap()).unwrap();
+            let socket = TcpStream::connect("localhost:8096").unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut stream = StreamOwned::new(connection, socket);
+            stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+
+            let first = String::from_utf8(read_message(&mut stream)).unwrap();
+
+            // Persistent connection works over TLS too
+            stream.write_all(last.as_bytes()).unwrap();
+            let second = String::from_utf8(read_message(&mut stream)).unwrap();
+
+            let certificate = stream.conn.peer_certificates().unwrap()[0].clone();
+
+            (certificate, first, second)
+        };
+
+        let close = "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let (certificate, first, second) = connect("localhost", &default_cert, close);
+        assert_eq!(certificate, default_cert);
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(first.ends_with("Hello, /hello!"));
+        assert!(second.ends_with("Hello, /hello!"));
+
+        // HSTS is added over TLS, to errors of event loop too
+        assert!(first.contains("Strict-Transport-Security: max-age=31536000\r\n"));
+
+        let (_, _, second) = connect("localhost", &default_cert, "GET /hello HTTP/1.1\r\nContent-Length: abc\r\n\r\n");
+        assert!(second.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", second);
+        assert!(second.contains("Strict-Transport-Security: max-age=31536000\r\n"));
+
+        // Certificate is chosen by SNI
+        let (certificate, _, _) = connect("chat.test", &named_cert, close);
+        assert_eq!(certificate, named_cert);
+
+        // Plain HTTP isn't answered
+        let mut stream = TcpStream::connect("localhost:8096").unwrap();
+        stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!contains_bytes(&read_response_bytes(&mut stream, 5), b"Hello"));
+
+        // New certificate is used for new connections after reload
+        let new_cert = generate("chat.test", "named");
+        certificates.reload().unwrap();
+
+        let (certificate, first, _) = connect("chat.test", &new_cert, close);
+        assert_eq!(certificate, new_cert);
+        assert!(first.ends_with("Hello, /hello!"));
+
+        // Invalid file leaves old certificates
+        fs::write(dir.join("named.key"), "invalid").unwrap();
+        assert!(certificates.reload().is_err());
+
+        let (certificate, _, _) = connect("chat.test", &new_cert, close);
+        assert_eq!(certificate, new_cert);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn https_redirect() {
+        let dir = std::env::temp_dir().join("talkback_well_known");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("acme-challenge")).unwrap();
+        fs::write(dir.join("acme-challenge/token"), "challenge").unwrap();
+
+        let server = Server::with_config("0.0.0.0:8097", Config {
+            redirect: Some(Redirect::new("0.0.0.0:8098").with_well_known(&dir)),
+            hsts: Some(String::from("max-age=31536000; includeSubDomains")),
+            ..Config::default()
+        });
+
+        server.add_handler("GET", "/hello", |_: &Request| Response::new(Status::Ok).with_body("Hello!"));
+
+        // Redirect to fixed origin
+        let _origin_server = Server::with_config("0.0.0.0:8099", Config {
+            redirect: Some(Redirect::new("0.0.0.0:8100").with_origin("https://chat.test:8443/")),
+            ..Config::default()
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let request = |port: u16, request: &str| {
+            let mut stream = TcpStream::connect(("localhost", port)).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+
+            read_response(&mut stream, 5)
+        };
+
+        // Main listener of plain HTTP doesn't add HSTS (it's checked over TLS in `tls` test)
+        let response = request(8097, "GET /hello HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("Strict-Transport-Security"));
+
+        // Every method and path is redirected to host of request with default port
+        let response = request(8098, "GET /hello?a=1&b=%20 HTTP/1.1\r\nHost: chat.test:8098\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", response);
+        assert!(response.contains("Location: https://chat.test/hello?a=1&b=%20\r\n"));
+        assert!(!response.contains("Strict-Transport-Security"));
+
+        let response = request(8098, "POST /api/message HTTP/1.1\r\nHost: [::1]:8098\r\nContent-Length: 2\r\n\r\nab");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("Location: https://[::1]/api/message\r\n"));
+
+        let response = request(8100, "GET /hello HTTP/1.1\r\nHost: other.test\r\n\r\n");
+        assert!(response.contains("Location: https://chat.test:8443/hello\r\n"));
+
+        // Host is required if origin isn't set
+        for host in ["", "Host: evil.test/path\r\n"].iter() {
+            let response = request(8098, &format!("GET /hello HTTP/1.1\r\n{}\r\n", host));
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", host);
+        }
+
+        // Well-known files are served as is
+        let response = request(8098, "GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: chat.test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nchallenge"));
+
+        for path in ["/.well-known/acme-challenge/missing", "/.well-known/../Cargo.toml"].iter() {
+            let response = request(8098, &format!("GET {} HTTP/1.1\r\nHost: chat.test\r\n\r\n", path));
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", path);
+        }
+
+        // Server with no directory redirects well-known paths too
+        let response = request(8100, "GET /.well-known/acme-challenge/token HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Location: https://chat.test:8443/.well-known/acme-challenge/token\r\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn websocket() {
+        /// Handler which echoes messages and remembers events
+        struct Echo {
+            events: Arc<Mutex<Vec<String>>>,
+            sockets: Arc<Mutex<Vec<WebSocket>>>,
+        }
+
+        impl WebSocketHandler for Echo {
+            fn on_open(&self, socket: &WebSocket) {
+                socket.send_text("welcome").unwrap();
+                self.sockets.lock().unwrap().push(socket.clone());
+            }
+
+            fn on_message(&self, socket: &WebSocket, message: WebSocketMessage) {
+                if message == WebSocketMessage::Text(String::from("close")) {
+                    socket.close(4000, "requested").unwrap();
+                } else {
+                    socket.send(message).unwrap();
+                }
+            }
+
+            fn on_close(&self, _: &WebSocket, code: u16, reason: &str) {
+                self.events.lock().unwrap().push(format!("close {} {}", code, reason));
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sockets = Arc::new(Mutex::new(Vec::new()));
+
+        let server = Server::with_config("0.0.0.0:8101", Config {
+            max_body_size: 4096,
+            max_frame_size: 1024,
+            ..Config::default()
+        });
+
+        // The second server checks idle connections often
+        let ping_server = Server::with_config("0.0.0.0:8102", Config {
+            ping_interval: Duration::from_secs(1),
+            ..Config::default()
+        });
+
+        for server in [&server, &ping_server].iter() {
+            let events = Arc::clone(&events);
+            let sockets = Arc::clone(&sockets);
+
+            server.add_handler("GET", "/ws", move |request: &Request| {
+                Response::upgrade(request, Echo {
+                    events: Arc::clone(&events),
+                    sockets: Arc::clone(&sockets),
+                })
+            });
+        }
+
+        thread::sleep(Duration::from_secs(1));
+
+        let connect = |port: u16| {
+            let mut stream = TcpStream::connect(("localhost", port)).unwrap();
+            stream.write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+            // Key of example from RFC 6455
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", response);
+            assert!(response.contains("Upgrade: websocket\r\n"));
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", response);
+
+            assert_eq!(read_frame(&mut stream), (0x1, b"welcome".to_vec()));
+
+            stream
+        };
+
+        let mut stream = connect(8101);
+
+        // Text and binary messages
+        write_frame(&mut stream, true, 0x1, "Привет".as_bytes(), true);
+        assert_eq!(read_frame(&mut stream), (0x1, "Привет".as_bytes().to_vec()));
+
+        write_frame(&mut stream, true, 0x2, &[0, 1, 2, 255], true);
+        assert_eq!(read_frame(&mut stream), (0x2, vec![0, 1, 2, 255]));
+
+        // Ping is answered between fragments of message
+        write_frame(&mut stream, false, 0x1, b"Hello", true);
+        write_frame(&mut stream, true, 0x9, b"ping", true);
+        write_frame(&mut stream, false, 0x0, b", ", true);
+        write_frame(&mut stream, true, 0x0, b"world!", true);
+        assert_eq!(read_frame(&mut stream), (0xA, b"ping".to_vec()));
+        assert_eq!(read_frame(&mut stream), (0x1, b"Hello, world!".to_vec()));
+
+        // Message of 16-bit length
+        let long = vec![b'a'; 1000];
+        write_frame(&mut stream, true, 0x1, &long, true);
+        assert_eq!(read_frame(&mut stream), (0x1, long));
+
+        // Server pushes message from another thread
+        let socket = sockets.lock().unwrap()[0].clone();
+        thread::spawn(move || socket.send_text("push").unwrap()).join().unwrap();
+        assert_eq!(read_frame(&mut stream), (0x1, b"push".to_vec()));
+
+        // Close frame of client is answered, then connection is closed
+        write_frame(&mut stream, true, 0x8, b"\x03\xe8bye", true);
+        assert_eq!(read_frame(&mut stream), (0x8, b"\x03\xe8bye".to_vec()));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(events.lock().unwrap().contains(&String::from("close 1000 bye")));
+
+        let socket = sockets.lock().unwrap()[0].clone();
+        assert!(!socket.is_open());
+        assert!(socket.send_text("late").is_err());
+
+        // Server starts closing
+        let mut stream = connect(8101);
+        write_frame(&mut stream, true, 0x1, b"close", true);
+        assert_eq!(read_frame(&mut stream), (0x8, b"\x0f\xa0requested".to_vec()));
+
+        // Messages after close frame are ignored
+        write_frame(&mut stream, true, 0x1, b"ignored", true);
+        write_frame(&mut stream, true, 0x8, b"\x0f\xa0", true);
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(events.lock().unwrap().contains(&String::from("close 4000 ")));
+
+        // Protocol violations close connection with code (frames are `fin`, opcode, payload and `masked`)
+        type Frame<'a> = (bool, u8, &'a [u8], bool);
+
+        let cases: [(&[Frame], u16); 8] = [
+            (&[(true, 0x1, b"unmasked", false)], 1002),
+            (&[(true, 0x1, &[b'a'; 2000], true)], 1009),
+            (&[(false, 0x1, &[b'a'; 1000], true), (false, 0x0, &[b'a'; 1000], true), (false, 0x0, &[b'a'; 1000], true), (false, 0x0, &[b'a'; 1000], true), (true, 0x0, &[b'a'; 1000], true)], 1009),
+            (&[(true, 0x1, &[0xff, 0xfe], true)], 1007),
+            (&[(true, 0x0, b"continuation", true)], 1002),
+            (&[(false, 0x9, b"fragmented ping", true)], 1002),
+            // Codes which are reserved for no status and closing with no frame
+            (&[(true, 0x8, b"\x03\xed", true)], 1002),
+            (&[(true, 0x8, b"\x03\xee", true)], 1002),
+        ];
+
+        for (frames, code) in cases.iter() {
+            let mut stream = connect(8101);
+
+            for (fin, opcode, payload, masked) in frames.iter() {
+                write_frame(&mut stream, *fin, *opcode, payload, *masked);
+            }
+
+            let (opcode, payload) = read_frame(&mut stream);
+            assert_eq!(opcode, 0x8);
+            assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), *code);
+        }
+
+        // Invalid handshakes
+        let handshakes = [
+            ("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", "426 Upgrade Required"),
+            ("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: short\r\nSec-WebSocket-Version: 13\r\n", "400 Bad Request"),
+            ("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n", "400 Bad Request"),
+        ];
+
+        for (headers, status) in handshakes.iter() {
+            let mut stream = TcpStream::connect("localhost:8101").unwrap();
+            stream.write_all(format!("GET /ws HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", response);
+
+            if status.starts_with("426") {
+                assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+            }
+        }
+
+        // Idle connection gets ping and is closed if it doesn't answer
+        let mut stream = connect(8102);
+        assert_eq!(read_frame(&mut stream), (0x9, Vec::new()));
+
+        stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(events.lock().unwrap().contains(&String::from("close 1006 ")));
+    }
+
+    #[test]
+    fn chat_websocket() {
+        let (_server, _session) = start_chat(8112, "ws_user");
+
+        let handshake = |query: &str| {
+            let mut stream = TcpStream::connect(("localhost", 8112)).unwrap();
+            stream.write_all(format!("GET /api/ws?{} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", query).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+
+            (stream, response)
+        };
+
+        // Connection isn't upgraded with no sign in
+        let (_, response) = handshake("login=ws_user&password=wrong");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+
+        let (mut stream, response) = handshake("login=ws_user&password=password");
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", response);
+
+        // Message which is sent by API is pushed to socket
+        post_message(8112, "ws_user", "from api");
+
+        let (opcode, payload) = read_frame(&mut stream);
+        let message: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(opcode, 0x1);
+        assert_eq!(message["id"], 0);
+        assert_eq!(message["author"], "ws_user");
+        assert_eq!(message["text"], "from api");
+
+        // Message of socket is added to chat and pushed back to it
+        write_frame(&mut stream, true, 0x1, b"from socket", true);
+
+        let (_, payload) = read_frame(&mut stream);
+        let message: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(message["id"], 1);
+        assert_eq!(message["text"], "from socket");
+    }
+
+    #[test]
+    fn event_stream() {
+        let streams = Arc::new(Mutex::new(Vec::new()));
+
+        let server = Server::with_config("0.0.0.0:8103", Config {
+            ping_interval: Duration::from_secs(1),
+            ..Config::default()
+        });
+
+        // Stream sends events after `Last-Event-ID`, then keeps handle for new ones
+        let streams_copy = Arc::clone(&streams);
+        server.add_handler("GET", "/events", move |request: &Request| {
+            let offset = request.header("Last-Event-ID")
+                .and_then(|e| e.parse::<usize>().ok())
+                .map(|e| e + 1)
+                .unwrap_or(0);
+
+            let streams = Arc::clone(&streams_copy);
+
+            Response::event_stream(move |stream| {
+                for id in offset..3 {
+                    stream.send(&ServerEvent::new(&format!("message {}", id)).with_id(&id.to_string())).unwrap();
+                }
+
+                streams.lock().unwrap().push(stream);
+            })
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect(("localhost", 8103)).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nAccept-Encoding: gzip\r\nLast-Event-ID: 0\r\n\r\n").unwrap();
+
+        // Body isn't delimited or compressed, so events reach client at once
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/event-stream\r\n"));
+        assert!(response.contains("Cache-Control: no-cache\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("Content-Length"));
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(!response.contains("Content-Encoding"));
+
+        let read_text = |stream: &mut TcpStream, expected: &str| {
+            let mut text = vec![0; expected.len()];
+            stream.read_exact(&mut text).unwrap();
+
+            assert_eq!(String::from_utf8(text).unwrap(), expected);
+        };
+
+        read_text(&mut stream, "id: 1\ndata: message 1\n\nid: 2\ndata: message 2\n\n");
+
+        // Server pushes event from another thread (multiline data is split, line breaks are removed from id)
+        let event_stream = streams.lock().unwrap()[0].clone();
+        thread::spawn(move || {
+            let event = ServerEvent::new("first\r\nsecond\nthird")
+                .with_id("3\n")
+                .with_event("update")
+                .with_retry(Duration::from_secs(2));
+
+            event_stream.send(&event).unwrap();
+        }).join().unwrap();
+
+        read_text(&mut stream, "id: 3\nevent: update\nretry: 2000\ndata: first\ndata: second\ndata: third\n\n");
+
+        // Idle stream gets comment
+        read_text(&mut stream, ":\n\n");
+
+        // Closing by client is noticed
+        let event_stream = streams.lock().unwrap()[0].clone();
+        drop(stream);
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(!event_stream.is_open());
+        assert!(event_stream.send(&ServerEvent::new("late")).is_err());
+
+        // Closing by server finishes response
+        let mut stream = TcpStream::connect(("localhost", 8103)).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        read_response(&mut stream, 5);
+        read_text(&mut stream, "id: 0\ndata: message 0\n\n");
+
+        streams.lock().unwrap()[1].close().unwrap();
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"id: 1\ndata: message 1\n\nid: 2\ndata: message 2\n\n");
+    }
+
+    #[test]
+    fn chat_events() {
+        let (_server, session) = start_chat(8113, "events_user");
+
+        {
+            let mut session = session.lock().unwrap();
+            let valid_session = session.auth("events_user", "password").unwrap();
+
+            valid_session.add_message("events_user", "first");
+            valid_session.add_message("events_user", "second");
+        }
+
+        let connect = |query: &str, last_event_id: &str| {
+            let mut stream = TcpStream::connect(("localhost", 8113)).unwrap();
+            stream.write_all(format!("GET /api/events?{} HTTP/1.1\r\nLast-Event-ID: {}\r\n\r\n", query, last_event_id).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+
+            (stream, response)
+        };
+
+        // Event with id and JSON of message
+        let read_event = |stream: &mut TcpStream| -> (String, Value) {
+            let mut event = Vec::new();
+            let mut byte = [0; 1];
+
+            while !event.ends_with(b"\n\n") {
+                stream.read_exact(&mut byte).unwrap();
+                event.push(byte[0]);
+            }
+
+            let event = String::from_utf8(event).unwrap();
+            let id = event.lines().find_map(|e| e.strip_prefix("id: ")).unwrap().to_string();
+            let data = event.lines().find_map(|e| e.strip_prefix("data: ")).unwrap();
+
+            assert!(event.contains("event: message\n"));
+
+            (id, serde_json::from_str(data).unwrap())
+        };
+
+        // Stream isn't opened with no sign in
+        let (_, response) = connect("login=events_user&password=wrong", "0");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+
+        // Messages after `Last-Event-ID` are sent on reconnect, then new ones are pushed
+        let (mut stream, response) = connect("login=events_user&password=password", "0");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let (id, message) = read_event(&mut stream);
+        assert_eq!(id, "1");
+        assert_eq!(message["text"], "second");
+
+        // The last possible id doesn't overflow (there is nothing after it)
+        let (mut last_stream, response) = connect("login=events_user&password=password", "18446744073709551615");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        thread::sleep(Duration::from_millis(200));
+        post_message(8113, "events_user", "third");
+
+        for stream in [&mut stream, &mut last_stream].iter_mut() {
+            let (id, message) = read_event(stream);
+            assert_eq!(id, "2");
+            assert_eq!(message["author"], "events_user");
+            assert_eq!(message["text"], "third");
+        }
+    }
+
+    #[test]
+    fn deferred_response() {
+        let waiting = Arc::new(Mutex::new(Vec::new()));
+
+        let server = Server::with_config("0.0.0.0:8111", Config {
+            max_threads_number: 1,
+            ..Config::default()
+        });
+
+        // Requests wait for wake with no busy worker thread (handler's response is sent at timeout)
+        let waiting_copy = Arc::clone(&waiting);
+        server.add_handler("GET", "/wait", move |_: &Request| {
+            let waiting = Arc::clone(&waiting_copy);
+
+            Response::new(Status::Ok)
+                .with_body("timeout")
+                .deferred(Duration::from_secs(1), move |deferred| waiting.lock().unwrap().push(deferred))
+        });
+
+        let waiting_copy = Arc::clone(&waiting);
+        server.add_handler("POST", "/wake", move |_: &Request| {
+            for deferred in waiting_copy.lock().unwrap().drain(..) {
+                let _ = deferred.respond(Response::new(Status::Ok).with_body("woken"));
+            }
+
+            Response::new(Status::Ok).with_body("done")
+        });
+
+        server.add_handler("GET", "/hello", |_: &Request| Response::new(Status::Ok).with_body("Hello!"));
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut streams = (0..3)
+            .map(|_| TcpStream::connect(("localhost", 8111)).unwrap())
+            .collect::<Vec<TcpStream>>();
+
+        for stream in streams.iter_mut() {
+            stream.write_all(b"GET /wait HTTP/1.1\r\n\r\n").unwrap();
+        }
+
+        thread::sleep(Duration::from_millis(200));
+
+        // The only worker thread is free while requests wait
+        let mut stream = TcpStream::connect(("localhost", 8111)).unwrap();
+        stream.write_all(b"POST /wake HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("done"));
+
+        // Connections are kept after deferred responses
+        for stream in streams.iter_mut() {
+            assert!(read_response(stream, 5).ends_with("woken"));
+
+            stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+            assert!(read_response(stream, 5).ends_with("Hello!"));
+        }
+
+        // Request which isn't woken gets response of handler, then handle can't answer it
+        let started = Instant::now();
+        stream.write_all(b"GET /wait HTTP/1.1\r\n\r\n").unwrap();
+
+        assert!(read_response(&mut stream, 5).ends_with("timeout"));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        let deferred = waiting.lock().unwrap().pop().unwrap();
+        assert!(!deferred.is_open());
+        assert!(deferred.respond(Response::new(Status::Ok)).is_err());
+    }
+
+    #[test]
+    fn chat_messages() {
+        let (_server, session) = start_chat(8114, "poll_user");
+
+        session.lock().unwrap().auth("poll_user", "password").unwrap().add_message("poll_user", "first");
+
+        // Status and JSON of response to request of messages
+        let get = |stream: &mut TcpStream, query: &str| -> (String, Value) {
+            stream.write_all(format!("GET /api/messages?{} HTTP/1.1\r\n\r\n", query).as_bytes()).unwrap();
+
+            let response = read_response(stream, 5);
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+            (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+        };
+
+        let mut stream = TcpStream::connect(("localhost", 8114)).unwrap();
+
+        let (status, _) = get(&mut stream, "login=poll_user&password=wrong");
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        // Invalid `after` (the last possible id has no next one)
+        for after in ["abc", "18446744073709551615"].iter() {
+            let (status, body) = get(&mut stream, &format!("login=poll_user&password=password&after={}", after));
+            assert_eq!(status, "HTTP/1.1 400 Bad Request");
+            assert_eq!(body, json!({ "result": "Invalid after!" }));
+        }
+
+        let (status, body) = get(&mut stream, "login=poll_user&password=password");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["result"][0]["text"], "first");
+
+        let (_, body) = get(&mut stream, "login=poll_user&password=password&after=0");
+        assert_eq!(body, json!({ "result": [] }));
+
+        // Request waits for the next message
+        let poster = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(300));
+            post_message(8114, "poll_user", "second");
+        });
+
+        let started = Instant::now();
+        let (status, body) = get(&mut stream, "login=poll_user&password=password&after=0&wait=5");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["result"][0]["id"], 1);
+        assert_eq!(body["result"][0]["text"], "second");
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        poster.join().unwrap();
+
+        // Empty list is sent when time expires
+        let started = Instant::now();
+        let (_, body) = get(&mut stream, "login=poll_user&password=password&after=1&wait=1");
+        assert_eq!(body, json!({ "result": [] }));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn middleware() {
+        let server = Server::new("0.0.0.0:8104", 5);
+
+        server.add_handler("GET", "/api/users/:id", |request: &Request| {
+            Response::new(Status::Ok).with_body(format!("{} {}", request.param("id").unwrap(), request.param("user").unwrap_or("-")))
+        });
+
+        server.add_handler("GET", "/apis", |request: &Request| {
+            Response::new(Status::Ok).with_body(request.header("X-Token").unwrap_or("-").to_string())
+        });
+
+        server.add_error_handler(RequestError::NotFound, |_: &Request| {
+            Response::new(Status::NotFound).with_body("custom 404")
+        });
+
+        // Middlewares are run in order of adding and wrap each other
+        server.add_middleware("/", |request: &mut Request, next: &Next| {
+            let mut response = next.run(request);
+            response.add_header("X-Chain", "global");
+            response
+        });
+
+        // Scoped middleware checks token and passes user to handler
+        server.add_middleware("/api/", |request: &mut Request, next: &Next| {
+            match request.header("X-Token") {
+                Some("secret") => {
+                    request.set_param("user", "admin");
+                    request.headers_mut().remove("X-Token");
+
+                    let mut response = next.run(request);
+                    response.add_header("X-Chain", "api");
+                    response
+                },
+                Some("missing") => Response::from_error(RequestError::NotFound),
+                _ => Response::new(Status::Unauthorized),
+            }
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let request = |request: &str| {
+            let mut stream = TcpStream::connect(("localhost", 8104)).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+
+            read_response(&mut stream, 5)
+        };
+
+        let response = request("GET /api/users/7 HTTP/1.1\r\nX-Token: secret\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n7 admin"));
+        assert!(response.find("X-Chain: api\r\n").unwrap() < response.find("X-Chain: global\r\n").unwrap());
+
+        // Request is stopped by middleware
+        let response = request("GET /api/users/7 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+        assert!(response.contains("X-Chain: global\r\n"));
+        assert!(!response.contains("X-Chain: api"));
+
+        // Middleware passes request to error handler
+        let response = request("GET /api/users/7 HTTP/1.1\r\nX-Token: missing\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        assert!(response.ends_with("custom 404"));
+
+        // Prefix matches whole segments
+        let response = request("GET /apis HTTP/1.1\r\nX-Token: other\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("other"));
+
+        // Global middleware is run for unknown paths too
+        let response = request("GET /unknown HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.contains("X-Chain: global\r\n"));
+    }
+
+    #[test]
+    fn handler_panic() {
+        /// Body which fails in the middle of response
+        struct BrokenReader(bool);
+
+        impl Read for BrokenReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 {
+                    panic!("broken body");
+                }
+
+                self.0 = true;
+                buf[..5].copy_from_slice(b"start");
+                Ok(5)
+            }
+        }
+
+        /// WebSocket handler which fails on every message
+        struct BrokenSocket;
+
+        impl WebSocketHandler for BrokenSocket {
+            fn on_message(&self, _: &WebSocket, _: WebSocketMessage) {
+                panic!("broken socket");
+            }
+        }
+
+        // The only worker thread must survive all panics
+        let server = Server::new("0.0.0.0:8105", 1);
+
+        server.add_handler("GET", "/panic", |_: &Request| -> Response {
+            panic!("handler failed");
+        });
+
+        server.add_handler("GET", "/body", |_: &Request| {
+            Response::new(Status::Ok).with_stream(BrokenReader(false))
+        });
+
+        server.add_handler("GET", "/ws", |request: &Request| Response::upgrade(request, BrokenSocket));
+
+        server.add_handler("GET", "/hello", |_: &Request| Response::new(Status::Ok).with_body("hello"));
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect(("localhost", 8105)).unwrap();
+
+        // Default response is sent, connection is kept
+        stream.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+
+        stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("hello"));
+
+        // Error handler is used
+        server.add_error_handler(RequestError::InternalServerError, |request: &Request| {
+            Response::new(Status::InternalServerError).with_body(format!("failed {}", request.path()))
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        stream.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.ends_with("failed /panic"));
+
+        // Panic of error handler gives default response
+        server.add_error_handler(RequestError::InternalServerError, |_: &Request| -> Response {
+            panic!("error handler failed");
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        stream.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+
+        // Headers of broken body are sent already, so connection is closed
+        let mut stream = TcpStream::connect(("localhost", 8105)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /body HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(!response.ends_with(b"0\r\n\r\n"));
+
+        // WebSocket is closed with internal error
+        let mut stream = TcpStream::connect(("localhost", 8105)).unwrap();
+        stream.write_all(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+
+        write_frame(&mut stream, true, 0x1, b"hello", true);
+        assert_eq!(read_frame(&mut stream), (0x8, 1011u16.to_be_bytes().to_vec()));
+
+        // Worker thread is still alive
+        let mut stream = TcpStream::connect(("localhost", 8105)).unwrap();
+        stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("hello"));
+    }
+
+    #[test]
+    fn reload() {
+        let server = Server::new("0.0.0.0:8108", 1);
+
+        server.add_handler("POST", "/echo", |request: &Request| Response::new(Status::Ok).with_body(request.body().to_vec()));
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect(("localhost", 8108)).unwrap();
+
+        let request = "POST /echo HTTP/1.1\r\nContent-Length: 20\r\n\r\n01234567890123456789";
+        stream.write_all(request.as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("01234567890123456789"));
+
+        // Requests which are received in part before reload
+        let mut partial_body = TcpStream::connect(("localhost", 8108)).unwrap();
+        partial_body.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 20\r\n\r\n01234").unwrap();
+
+        let mut partial_head = TcpStream::connect(("localhost", 8108)).unwrap();
+        partial_head.write_all(format!("POST /echo HTTP/1.1\r\nX-Padding: {}", "x".repeat(2000)).as_bytes()).unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+
+        // New limit is used on the same connection
+        server.reload(Config {
+            max_body_size: 10,
+            max_headers_size: 1024,
+            ..Config::default()
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        stream.write_all(request.as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        // Requests in progress are checked with new limits, and server keeps working
+        partial_body.write_all(b"567890123456789").unwrap();
+        assert!(read_response(&mut partial_body, 5).starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        partial_head.write_all(b"\r\n\r\n").unwrap();
+        assert!(read_response(&mut partial_head, 5).starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        // Limits can be as big as possible with no overflow
+        server.reload(Config {
+            max_body_size: usize::MAX,
+            max_headers_size: usize::MAX,
+            ..Config::default()
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        let mut stream = TcpStream::connect(("localhost", 8108)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("01234567890123456789"));
+
+        // Number of worker threads is kept
+        server.reload(Config {
+            max_threads_number: 0,
+            ..Config::default()
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        let mut stream = TcpStream::connect(("localhost", 8108)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).ends_with("01234567890123456789"));
+    }
+
+    #[test]
+    fn slow_clients() {
+        let server = Server::with_config("0.0.0.0:8109", Config {
+            keep_alive_timeout: Duration::from_secs(1),
+            header_timeout: Duration::from_secs(1),
+            body_timeout: Duration::from_secs(1),
+            write_timeout: Duration::from_secs(1),
+            min_transfer_rate: 100,
+            max_headers_number: 5,
+            max_headers_size: 256,
+            ..Config::default()
+        });
+
+        server.add_handler("POST", "/echo", |request: &Request| Response::new(Status::Ok).with_body(request.body().to_vec()));
+        server.add_handler("GET", "/big", |_: &Request| Response::new(Status::Ok).with_body(vec![b'a'; 32 * 1024 * 1024]));
+
+        thread::sleep(Duration::from_secs(1));
+
+        let connect = || {
+            let stream = TcpStream::connect(("localhost", 8109)).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream
+        };
+
+        // Headers which are sent byte by byte get 408 even though client isn't idle
+        let mut stream = connect();
+        let start = Instant::now();
+
+        stream.write_all(b"GET /echo HTTP/1.1\r\n").unwrap();
+
+        for byte in b"X-Slow: 1234567890".iter() {
+            if stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        // Body which isn't received in time
+        let mut stream = connect();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 1000\r\n\r\n0123456789").unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        // Timeout of body is extended while client sends it with minimal rate
+        let mut stream = connect();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 300\r\n\r\n").unwrap();
+
+        for _ in 0..3 {
+            stream.write_all(&[b'b'; 100]).unwrap();
+            thread::sleep(Duration::from_millis(700));
+        }
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with(&"b".repeat(300)));
+
+        // Limits of headers
+        let many = (0..6).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect::<String>();
+        let long = format!("X-Long: {}\r\n", "a".repeat(300));
+
+        for head in [many, long.clone()].iter() {
+            let mut stream = connect();
+            stream.write_all(format!("GET /echo HTTP/1.1\r\n{}\r\n", head).as_bytes()).unwrap();
+
+            let response = read_response(&mut stream, 5);
+            assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+        }
+
+        // Too long head is rejected before its end
+        let mut stream = connect();
+        stream.write_all(format!("GET /echo HTTP/1.1\r\n{}", long).as_bytes()).unwrap();
+        assert!(read_response(&mut stream, 5).starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        // Idle connection is closed with no response
+        let mut stream = connect();
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        // Client which doesn't read response is disconnected
+        let mut stream = connect();
+        stream.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+
+        thread::sleep(Duration::from_secs(3));
+
+        let mut response = Vec::new();
+
+        if stream.read_to_end(&mut response).is_ok() {
+            assert!(response.len() < 32 * 1024 * 1024);
+        }
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let server = Arc::new(Server::new("0.0.0.0:8106", 2));
+
+        let slow = |_: &Request| {
+            thread::sleep(Duration::from_secs(1));
+            Response::new(Status::Ok).with_body("slow")
+        };
+
+        server.add_handler("GET", "/slow", slow);
+        server.add_handler("GET", "/hello", |_: &Request| Response::new(Status::Ok).with_body("hello"));
+        server.add_handler("GET", "/events", |_: &Request| Response::event_stream(|_| ()));
+
+        thread::sleep(Duration::from_secs(1));
+
+        // Idle persistent connection
+        let mut idle = TcpStream::connect(("localhost", 8106)).unwrap();
+        idle.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut idle, 5).ends_with("hello"));
+
+        // Open event stream
+        let mut events = TcpStream::connect(("localhost", 8106)).unwrap();
+        events.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut events, 5).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Request which is being processed
+        let mut busy = TcpStream::connect(("localhost", 8106)).unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+
+        let server_copy = Arc::clone(&server);
+        let shutdown = thread::spawn(move || server_copy.shutdown(Duration::from_secs(5)));
+
+        thread::sleep(Duration::from_millis(200));
+
+        // New connections aren't accepted, idle ones and streams are closed
+        assert!(TcpStream::connect(("localhost", 8106)).is_err());
+        assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(events.read(&mut [0; 1]).unwrap(), 0);
+
+        // Request is finished, then connection is closed
+        let response = read_response(&mut busy, 5);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("slow"));
+        assert_eq!(busy.read(&mut [0; 1]).unwrap(), 0);
+
+        assert_eq!(shutdown.join().unwrap(), ShutdownReport {
+            drained: 3,
+            cut_requests: 0,
+            cut_streams: 0,
+            detached_threads: 0,
+        });
+
+        // The second shutdown has nothing to do
+        assert_eq!(server.shutdown(Duration::from_secs(1)), ShutdownReport::default());
+
+        // Request which isn't finished before deadline is cut off
+        let server = Server::new("0.0.0.0:8107", 1);
+
+        server.add_handler("GET", "/slow", |_: &Request| {
+            thread::sleep(Duration::from_secs(3));
+            Response::new(Status::Ok)
+        });
+
+        thread::sleep(Duration::from_secs(1));
+
+        let mut busy = TcpStream::connect(("localhost", 8107)).unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        let report = server.shutdown(Duration::from_millis(500));
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(report, ShutdownReport {
+            drained: 0,
+            cut_requests: 1,
+            cut_streams: 0,
+            detached_threads: 1,
+        });
+
+        busy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(busy.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    /// Send frame of client (masked if `masked` is set)
+    fn write_frame(stream: &mut TcpStream, fin: bool, opcode: u8, payload: &[u8], masked: bool) {
+        let mask_bit = if masked { 0x80 } else { 0 };
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+
+        match payload.len() {
+            length if length < 126 => frame.push(mask_bit | length as u8),
+            length => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+        }
+
+        if masked {
+            let mask = [0x12, 0x34, 0x56, 0x78];
+
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, e)| e ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// Read frame of server (opcode and payload)
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+
+        // Frames of server are final and unmasked
+        assert_eq!(head[0] & 0xF0, 0x80);
+        assert_eq!(head[1] & 0x80, 0);
+
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                stream.read_exact(&mut length).unwrap();
+
+                u16::from_be_bytes(length) as usize
+            },
+            length => length as usize,
+        };
+
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+
+        (head[0] & 0x0F, payload)
+    }
+
+    /// Start server with chat API of `main` and user `login` with password `password`
+    fn start_chat(port: u16, login: &str) -> (Server, Arc<Mutex<AnonymSession>>) {
+        let server = Server::new(&format!("0.0.0.0:{}", port), 5);
+        let session = Arc::new(Mutex::new(AnonymSession::new()));
+
+        // User can be kept in users storage by previous run
+        let _ = session.lock().unwrap().register(login, "password");
+
+        crate::add_api(&server, &session);
+
+        thread::sleep(Duration::from_secs(1));
+
+        (server, session)
+    }
+
+    /// Send message to chat by API
+    fn post_message(port: u16, login: &str, text: &str) {
+        let body = format!("login={}&password=password&message={}", login, text.replace(' ', "+"));
+
+        let mut stream = TcpStream::connect(("localhost", port)).unwrap();
+        stream.write_all(format!("POST /api/message HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+            Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+
+        let response = read_response(&mut stream, 5);
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+    }
+
+    /// Read one response from stream (headers and body of Content-Length size or chunked one)
+    fn read_response(stream: &mut TcpStream, timeout: u64) -> String {
+        String::from_utf8(read_response_bytes(stream, timeout)).unwrap()
+    }
+
+    fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|e| e == needle)
+    }
+
+    /// Read one response from stream with no utf-8 conversion (for binary bodies)
+    fn read_response_bytes(stream: &mut TcpStream, timeout: u64) -> Vec<u8> {
+        stream.set_read_timeout(Some(Duration::from_secs(timeout))).unwrap();
+
+        read_message(stream)
+    }
+
+    /// Read one response from any stream (timeout must be set on its socket)
+    fn read_message<S: Read>(stream: &mut S) -> Vec<u8> {
+        let mut response = Vec::new();
+
+        // Read byte by byte to leave the next pipelined response in stream
+        let mut buffer = [0; 1];
+
+        loop {
+            if let Some(position) = response.windows(4).position(|e| e == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&response[..position]).to_lowercase();
+                if head.contains("transfer-encoding: chunked") {
+                    if response.ends_with(b"\r\n0\r\n\r\n") {
+                        break;
+                    }
+                } else {
+                    let length = head.lines()
+                        .find_map(|e| e.strip_prefix("content-length:"))
+                        .map(|e| e.trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+
+                    if response.len() >= position + 4 + length {
+                        break;
+                    }
+                }
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(size) => response.extend_from_slice(&buffer[..size]),
+            }
+        }
+
+        response
     }
 }
\ No newline at end of file
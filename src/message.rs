@@ -1,18 +1,41 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+#[derive(Serialize)]
 pub struct Message {
     id: usize,
+    #[serde(rename = "author")]
     login: String,
     text: String,
+    /// Unix time of sending in seconds
+    timestamp: u64,
 }
 
 impl Message {
     pub fn new(id: usize, login: String, text: String) -> Message {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|e| e.as_secs())
+            .unwrap_or(0);
+
         Message {
             id,
             login,
-            text
+            text,
+            timestamp,
         }
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Message as plain text line (`format` marks login up for html)
+    pub fn format_text(&self) -> String {
+        format!("{}: {}", self.login, self.text)
+    }
+
     pub fn format(&self) -> String {
         format!("<b>{}</b>: {}", self.login, self.text)
     }
@@ -24,6 +47,7 @@ impl Clone for Message {
             id: self.id,
             login: String::from(&self.login),
             text: String::from(&self.text),
+            timestamp: self.timestamp,
         }
     }
-}
\ No newline at end of file
+}
@@ -1,337 +1,365 @@
-use std::collections::HashMap;
-use std::io::{Read, Write, self};
-use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, Sender};
-use std::thread;
-use std::str::{self, Utf8Error};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use mio::{Poll, Token, Waker};
+
+use connection::Flow;
+use deferred::Waiting;
+use event_loop::{EventLoop, WAKER};
+use router::Pattern;
+
+pub use deferred::Deferred;
+pub use event_stream::{EventStream, ServerEvent};
+pub use form::Form;
+pub use headers::Headers;
+pub use middleware::{Middleware, Next};
+pub use redirect::Redirect;
+pub use request::Request;
+pub use response::{Body, Response, Status};
+pub use static_files::StaticFiles;
+pub use websocket::{WebSocket, WebSocketHandler, WebSocketMessage};
+#[cfg(feature = "tls")]
+pub use tls::{Certificates, TlsError};
+
+mod compression;
+mod connection;
+mod deferred;
+mod event_loop;
+mod event_stream;
+mod form;
+mod headers;
+mod middleware;
+mod pool;
+mod range;
+mod redirect;
+mod request;
+mod response;
+mod router;
+mod static_files;
+mod websocket;
+#[cfg(feature = "tls")]
+mod tls;
+
+/// How often queue of requests and connections are checked for expired ones
+const QUEUE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Processor of requests (closures `Fn(&Request) -> Response` are handlers too)
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
 
-type Job = Box<dyn Fn(&HashMap<String, String>, &Vec<String>, &str) -> (Vec<String>, String) + Sync + Send>;
+type Job = Box<dyn Handler>;
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 /// Possible request's error for this server
 pub enum RequestError {
     NotFound,
+    MethodNotAllowed,
     BadRequest,
+    PayloadTooLarge,
     ServiceUnavailable,
+    /// Handler (or middleware) was panicked
+    InternalServerError,
+    /// Request wasn't received in time
+    RequestTimeout,
+    /// Too many header fields or too big head of request
+    RequestHeaderFieldsTooLarge,
+}
+
+impl RequestError {
+    /// Status of response which is sent when no error handler was added
+    fn status(&self) -> Status {
+        match self {
+            RequestError::NotFound => Status::NotFound,
+            RequestError::MethodNotAllowed => Status::MethodNotAllowed,
+            RequestError::BadRequest => Status::BadRequest,
+            RequestError::PayloadTooLarge => Status::PayloadTooLarge,
+            RequestError::ServiceUnavailable => Status::ServiceUnavailable,
+            RequestError::InternalServerError => Status::InternalServerError,
+            RequestError::RequestTimeout => Status::RequestTimeout,
+            RequestError::RequestHeaderFieldsTooLarge => Status::RequestHeaderFieldsTooLarge,
+        }
+    }
+}
+
+/// Server settings
+pub struct Config {
+    /// Number of worker threads (maximal number of requests processed at the same time)
+    pub max_threads_number: usize,
+    /// Maximal number of requests waiting for free worker thread
+    pub queue_size: usize,
+    /// Maximal time of waiting for free worker thread (then 503 is sent)
+    pub queue_timeout: Duration,
+    /// Maximal size of request's body in bytes (bigger ones get 413) and of WebSocket message
+    pub max_body_size: usize,
+    /// Maximal size of WebSocket frame's payload in bytes
+    pub max_frame_size: usize,
+    /// Idle time of WebSocket connection after which ping is sent (connection is closed if no answer in the same time).
+    /// Idle event streams get comment in the same interval.
+    pub ping_interval: Duration,
+    /// Time to wait for the next request on persistent connection (and for the first one on new connection)
+    pub keep_alive_timeout: Duration,
+    /// Maximal time of receiving request line and headers after the first byte of request (then 408 is sent)
+    pub header_timeout: Duration,
+    /// Maximal time of receiving request's body (then 408 is sent), it's extended with `min_transfer_rate`
+    pub body_timeout: Duration,
+    /// Maximal time of sending queued data (it's extended with `min_transfer_rate`).
+    /// Connection is closed if client doesn't read data in time or reads nothing for this time.
+    pub write_timeout: Duration,
+    /// Minimal speed of slow client in bytes per second: body and write timeouts are extended
+    /// by one second for every `min_transfer_rate` bytes (0 disables extension)
+    pub min_transfer_rate: usize,
+    /// Maximal number of request's header fields (then 431 is sent)
+    pub max_headers_number: usize,
+    /// Maximal size of request line and headers in bytes (then 431 is sent)
+    pub max_headers_size: usize,
+    /// Maximal number of requests served on one connection
+    pub max_requests_per_connection: usize,
+    /// Minimal size of body in bytes which is compressed (if client accepts it)
+    pub compression_threshold: usize,
+    /// Certificates of HTTPS server (plain HTTP is served if they are not set)
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<Certificates>>,
+    /// Second plain HTTP listener which redirects requests to HTTPS
+    pub redirect: Option<Redirect>,
+    /// Value of `Strict-Transport-Security` header (e.g. `max-age=31536000`).
+    /// It's added to responses of main listener only if `tls` is set (browsers ignore it over plain HTTP).
+    pub hsts: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_threads_number: 5,
+            queue_size: 32,
+            queue_timeout: Duration::from_secs(2),
+            max_body_size: 1024 * 1024,
+            max_frame_size: 64 * 1024,
+            ping_interval: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            min_transfer_rate: 500,
+            max_headers_number: 100,
+            max_headers_size: 8 * 1024,
+            max_requests_per_connection: 100,
+            compression_threshold: 1024,
+            #[cfg(feature = "tls")]
+            tls: None,
+            redirect: None,
+            hsts: None,
+        }
+    }
+}
+
+/// Settings of handler's route
+#[derive(Debug, Clone, Copy)]
+pub struct RouteOptions {
+    /// Compress responses with coding from `Accept-Encoding`
+    pub compress: bool,
+}
+
+impl Default for RouteOptions {
+    fn default() -> RouteOptions {
+        RouteOptions {
+            compress: true,
+        }
+    }
+}
+
+/// Connections which were served until the end of graceful shutdown and which were cut off
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connections which were finished before deadline
+    pub drained: usize,
+    /// Connections which were reading request, waiting for handler or sending response at deadline
+    pub cut_requests: usize,
+    /// WebSocket connections and event streams which were open at deadline
+    pub cut_streams: usize,
+    /// Worker threads which were still running handlers at deadline (they are left detached)
+    pub detached_threads: usize,
 }
 
 enum Impulse {
-    Handler(String, String, Job),
+    Handler(String, Pattern, Job, RouteOptions),
     ErrorHandler(RequestError, Job),
-    Request(TcpStream),
-    Shutdown,
+    /// Middleware and prefix of paths which it's run for
+    Middleware(String, Box<dyn Middleware>),
+    /// Part of response which is being sent by worker thread (flow is released when it's sent)
+    Data(Token, Vec<u8>, Arc<Flow>),
+    /// The last part of response and `true` if connection can be used for the next request
+    Response(Token, Vec<u8>, bool),
+    /// Response of handshake after which connection is switched to WebSocket
+    Upgrade(Token, Vec<u8>, Arc<dyn WebSocketHandler>, WebSocket),
+    /// Headers of event stream after which connection is used for events only
+    Stream(Token, Vec<u8>, EventStream),
+    /// Request which waits for response of handle (response of handler is sent at deadline)
+    Defer(Token, Waiting),
+    /// Frame which is sent to WebSocket connection (or event which is sent to event stream)
+    Frame(Token, Vec<u8>),
+    /// Finishing of event stream by server
+    End(Token),
+    /// Closing of WebSocket connection with code and reason
+    Close(Token, u16, String),
+    /// Event of WebSocket connection was processed, so the next message can be read
+    Processed(Token),
+    /// New settings of server
+    Reload(Box<Config>),
+    /// Graceful shutdown which must be finished before deadline
+    Shutdown(Instant),
 }
 
 pub struct Server {
     controller_tx: Sender<Impulse>,
+    waker: Arc<Waker>,
+    /// Main server thread (`None` after shutdown)
+    event_loop: Mutex<Option<JoinHandle<ShutdownReport>>>,
 }
 
 impl Server {
     /// Start new server and binding on giving addr
     pub fn new(addr: &str, max_threads_number: usize) -> Server {
-        // Start binding on addr
-        let listener = TcpListener::bind(addr).unwrap();
+        Server::with_config(addr, Config {
+            max_threads_number,
+            ..Config::default()
+        })
+    }
+
+    /// Start new server with specified settings and binding on giving addr
+    pub fn with_config(addr: &str, config: Config) -> Server {
+        // Start binding on addr (and on addr of redirect listener)
+        let listener = bind(addr);
+        let redirect_listener = config.redirect.as_ref().map(|e| bind(&e.addr));
+
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
 
         // Create channel for data exchange
         let (controller_tx, controller_rx) = mpsc::channel();
-        let controller_tx_copy = controller_tx.clone();
-        
+
+        let event_loop = EventLoop::new(
+            poll,
+            listener,
+            redirect_listener,
+            config,
+            controller_tx.clone(),
+            controller_rx,
+            Arc::clone(&waker),
+        ).unwrap();
+
         // Start main server thread
-        thread::spawn(move || {
-            println!("i: main server thread is started");
-
-            // Catch income connection and send it to controller thread
-            for stream in listener.incoming() {
-                if let Err(error) = controller_tx.send(Impulse::Request(stream.unwrap())) {
-                    println!("e: main server thread is stopped: {}", &error);
-                    break;
-                }
-            }
-            
-            println!("i: main server thread is stopped");
-        });
-
-        // Start controller thread
-        thread::spawn(move || {
-            println!("i: controller thread is started");
-
-            let mut handlers: Arc<HashMap<String, Job>> = Arc::new(HashMap::new());
-            let mut error_handlers: Arc<HashMap<RequestError, Job>> = Arc::new(HashMap::new());
-
-            // Start processing loop
-            loop {
-                let impulse = controller_rx.recv().unwrap();
-
-                // Proccess request
-                if let Impulse::Request(mut stream) = impulse {
-                    println!("i: got Request impulse");
-
-                    // Check maximal number of connections
-                    if Arc::strong_count(&handlers) > max_threads_number {
-                        println!("i: max threads number was achieved");
-
-                        match error_handlers.get(&RequestError::ServiceUnavailable) {
-                            Some(closure) => {
-                                let result = closure(&HashMap::new(), &Vec::new(), "");
-                                Response::process(&mut stream, &result.0, &result.1).unwrap();
-                            },
-                            None => {
-                                let mut headers = Vec::new();
-                                headers.push(String::from("HTTP/1.1 503 Service Unavailable"));
-
-                                Response::process(&mut stream, &headers, "").unwrap();
-                            }
-                        }
-
-                        continue;
-                    }
-
-                    let handlers = Arc::clone(&handlers);
-                    let error_handlers = Arc::clone(&error_handlers);
-
-                    // Start processor thread
-                    thread::spawn(move || {
-                        match Request::process(&mut stream) {
-                            Ok(request) => {
-                                println!("i: connect {} {}", request.method, request.path);
-
-                                match handlers.get(&format!("{} {}", request.method, request.path)) {
-                                    Some(closure) => {
-                                        // Process request and run specified closure
-                                        let result = closure(&request.params, &request.headers, &request.body);
-                                        Response::process(&mut stream, &result.0, &result.1).unwrap();
-                                    },
-                                    None => {
-                                        println!("i: handler not found");
-
-                                        // 404 error
-                                        match error_handlers.get(&RequestError::NotFound) {
-                                            Some(closure) => {
-                                                let result = closure(&HashMap::new(), &Vec::new(), "");
-                                                Response::process(&mut stream, &result.0, &result.1).unwrap();
-                                            },
-                                            None => {
-                                                let mut headers = Vec::new();
-                                                headers.push(String::from("HTTP/1.1 404 Not Found"));
-                
-                                                Response::process(&mut stream, &headers, "").unwrap();
-                                            }
-                                        }
-                                        
-                                        return;
-                                    },
-                                };
-                            },
-                            Err(_) => {
-                                println!("i: incorrect request");
-
-                                // 400 error
-                                match error_handlers.get(&RequestError::BadRequest) {
-                                    Some(closure) => {
-                                        let result = closure(&HashMap::new(), &Vec::new(), "");
-                                        Response::process(&mut stream, &result.0, &result.1).unwrap();
-                                    },
-                                    None => {
-                                        let mut headers = Vec::new();
-                                        headers.push(String::from("HTTP/1.1 400 Bad Request"));
-        
-                                        Response::process(&mut stream, &headers, "").unwrap();
-                                    }
-                                }
-                
-                                return;
-                            },
-                        };
-                    });
-
-                    continue;
-                }
-
-                // Process handler (endpoint)
-                if let Impulse::Handler(method, path, closure) = impulse {
-                    println!("i: got Handler impulse");
-                    
-                    if let Some(handlers) = Arc::get_mut(&mut handlers) {
-                        handlers.insert(format!("{} {}", method, path), closure);
-                    }
-                    
-                    continue;
-                }
-
-                // Process error handler (like 400, 404 and 503)
-                if let Impulse::ErrorHandler(error, closure) = impulse {
-                    println!("i: got ErrorHandler impulse");
-                    
-                    if let Some(error_handlers) = Arc::get_mut(&mut error_handlers) {
-                        error_handlers.insert(error, closure);
-                    }
-
-                    continue;
-                }
-
-                // Process shutdown (break loop and stop server)
-                if let Impulse::Shutdown = impulse {
-                    println!("i: got Shutdown impulse");
-                    break;
-                }
-            }
-
-            println!("i: controller thread is stopped");
-        });
+        let event_loop = thread::spawn(move || event_loop.run());
 
         Server {
-            controller_tx: controller_tx_copy
+            controller_tx,
+            waker,
+            event_loop: Mutex::new(Some(event_loop)),
         }
     }
 
-    /// Add server endpoint with processor (closure)
-    pub fn add_handler(&self, method: &str, path: &str, closure: Job) {
-        self.controller_tx.send(Impulse::Handler(method.to_string(), path.to_string(), closure))
-            .expect("Fail to add new handler for server!");
+    /// Add server endpoint with processor (closure or `Handler`).
+    /// Path can contain named segments (`/api/rooms/:id`) and trailing wildcard (`/static/*file`),
+    /// their captured values are available with `Request::param`.
+    pub fn add_handler<H: Handler + 'static>(&self, method: &str, path: &str, handler: H) {
+        self.add_handler_with(method, path, handler, RouteOptions::default());
     }
 
-    /// Add error handler with processor (closure)
-    pub fn add_error_handler(&self, error: RequestError, closure: Job) {
-        self.controller_tx.send(Impulse::ErrorHandler(error, closure))
-            .expect("Fail to add new error handler for server!");
-    }
+    /// Add server endpoint with specified settings of route
+    pub fn add_handler_with<H: Handler + 'static>(&self, method: &str, path: &str, handler: H, options: RouteOptions) {
+        let pattern = Pattern::parse(path)
+            .expect("Fail to parse path of new handler for server!");
 
-    /// Stop server
-    pub fn stop(&self) {
-        self.controller_tx.send(Impulse::Shutdown)
-            .expect("Fail to shutdown the server!");
+        self.send(Impulse::Handler(method.to_string(), pattern, Box::new(handler), options))
+            .expect("Fail to add new handler for server!");
     }
-}
 
-impl Drop for Server {
-    fn drop(&mut self) {
-        self.stop();
-    }
-}
+    /// Serve files of directory `root` on GET requests to paths which start with `prefix`.
+    /// Prefix `/` catches every path (then other methods of all paths get 405), so use separate prefix like `/static`.
+    pub fn add_static<P: AsRef<Path>>(&self, prefix: &str, root: P) {
+        let path = format!("{}/*file", prefix.trim_end_matches('/'));
 
-struct Request {
-    method: String,
-    path: String,
-    params: HashMap<String, String>,
-    headers: Vec<String>,
-    body: String,
-}
-
-impl Request {
-    fn process(stream: &mut TcpStream) -> Result<Request, Utf8Error> {
-        let mut buffer = [0; 1024];
+        self.add_handler("GET", &path, StaticFiles::new(prefix, root));
+    }
 
-        // Read data from stream to buffer
-        if let Err(_) = stream.read(&mut buffer) {
-            println!("e: problems with reading from stream");
-        }
+    /// Add error handler with processor (closure or `Handler`), handler of the same error is replaced.
+    /// Handlers of errors which are found before request gets worker thread (400, 408, 413, 431 and 503)
+    /// are run by event loop, so they must not block (e.g. read pages from disk up front).
+    pub fn add_error_handler<H: Handler + 'static>(&self, error: RequestError, handler: H) {
+        self.send(Impulse::ErrorHandler(error, Box::new(handler)))
+            .expect("Fail to add new error handler for server!");
+    }
 
-        // Create utf-8 string from buffer of bytes
-        let buffer = str::from_utf8(&buffer)?
-            .replace("\r\n", "\n");
+    /// Add middleware which is run for requests with paths under `prefix` (`/` for all requests).
+    /// Middlewares are run in order of their adding, then handler is run.
+    pub fn add_middleware<M: Middleware + 'static>(&self, prefix: &str, middleware: M) {
+        self.send(Impulse::Middleware(prefix.to_string(), Box::new(middleware)))
+            .expect("Fail to add new middleware for server!");
+    }
 
-        // Trim zero-bytes
-        let buffer = buffer.trim_matches(char::from(0));
+    /// Replace settings of running server with no closing of connections.
+    /// Listeners, number of worker threads, size of queue, certificates and redirect listener can't be changed,
+    /// so they are kept (certificates are reloaded with `Certificates::reload`).
+    /// New limits are applied to requests which are received in part too.
+    pub fn reload(&self, config: Config) {
+        self.send(Impulse::Reload(Box::new(config)))
+            .expect("Fail to reload settings of server!");
+    }
 
-        // Split request to headers and body
-        let request = buffer.split("\n\n").map(String::from).collect::<Vec<String>>();
+    /// Stop server at once (requests which are being processed are cut off)
+    pub fn stop(&self) {
+        self.shutdown(Duration::from_secs(0));
+    }
 
-        // Split headers
-        let mut headers: Vec<String> = match request.get(0) {
-            Some(headers) => headers.split("\n").map(String::from).collect(),
-            None => Vec::new(),
+    /// Stop accepting connections, wait up to `timeout` for requests which are being processed,
+    /// then close all connections and join threads of server.
+    /// WebSocket connections get close frame and event streams are finished at once.
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let event_loop = match self.event_loop.lock().unwrap().take() {
+            Some(e) => e,
+            None => return ShutdownReport::default(),
         };
 
-        // Remove request line from headers and save it separately
-        let request_line = headers.remove(0);
+        // Main server thread can be stopped already by error
+        let _ = self.send(Impulse::Shutdown(Instant::now() + timeout));
 
-        // Split request line to parts (request method, path and version)
-        let request_line: Vec<String> = request_line.split(" ").map(String::from).collect();
+        event_loop.join().unwrap_or_else(|_| {
+            println!("e: main server thread was panicked");
+            ShutdownReport::default()
+        })
+    }
 
-        // Save request method
-        let method = match request_line.get(0) {
-            Some(e) => e.clone(),
-            None => {
-                println!("e: request processing error {:?}", buffer);
-                String::new()
-            }
-        };
-        
-        // Save request path
-        let path = match request_line.get(1) {
-            Some(e) => e.clone(),
-            None => {
-                println!("e: request processing error {:?}", buffer);
-                String::new()
-            }
-        };
+    /// Send impulse to main server thread and wake it up
+    fn send(&self, impulse: Impulse) -> Result<(), String> {
+        self.controller_tx.send(impulse).map_err(|e| e.to_string())?;
+        self.waker.wake().map_err(|e| e.to_string())
+    }
+}
 
-        // Split path to path and params (for GETs)
-        let path_with_params: Vec<&str> = path.split("?").collect();
-        let params: HashMap<String, String> = HashMap::new();
-
-        let (path, params) = if path_with_params.len() > 1 {
-            // Save clear path (with no params)
-            let path = match path_with_params.get(0) {
-                Some(e) => e.to_string(),
-                None => {
-                    println!("e: request processing error {:?}", buffer);
-                    String::new()
-                }
-            };
-
-            // Save params line
-            let params = match path_with_params.get(1) {
-                Some(e) => e.to_string(),
-                None => {
-                    println!("e: request processing error {:?}, {:?}", request_line, headers);
-                    String::new()
-                }
-            };
-
-            // Split params line to vector
-            let params = params.split("&").map(|e| {
-                let e = e.split("=").collect::<Vec<&str>>();
-    
-                if e.len() == 2 {
-                    (e.get(0).unwrap().to_string(), e.get(1).unwrap().to_string())
-                } else {
-                    (String::new(), String::new())
-                }
-            }).collect::<HashMap<String, String>>();
-
-            (path, params)
-        } else {
-            (path, params)
-        };
+/// Start non-blocking listener on addr
+fn bind(addr: &str) -> mio::net::TcpListener {
+    let listener = TcpListener::bind(addr).unwrap();
+    listener.set_nonblocking(true).unwrap();
 
-        // Process body part of request
-        let body = match request.get(1) {
-            Some(body) => body.to_string(),
-            None => String::new(),
-        };
+    mio::net::TcpListener::from_std(listener)
+}
 
-        Ok(
-            Request {
-                method,
-                path,
-                params,
-                headers,
-                body,
-            }
-        )
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
-struct Response;
-
-impl Response {
-    fn process(stream: &mut TcpStream, headers: &Vec<String>, body: &str) -> io::Result<usize> {
-        // Send response
-        stream.write(format!("{}\n\n{}",
-            headers.join("\n"),
-            body
-        ).as_bytes())
-    }
-}
\ No newline at end of file
@@ -0,0 +1,186 @@
+use std::io::{Read, self};
+
+use flate2::Compression;
+use flate2::read::{GzEncoder, ZlibEncoder};
+
+use super::{Body, Request, Response, Status};
+
+/// Content coding which is supported by server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    /// Supported codings in order of server's preference
+    const ALL: &'static [Coding] = &[
+        #[cfg(feature = "brotli")]
+        Coding::Brotli,
+        Coding::Gzip,
+        Coding::Deflate,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    /// Wrap stream with encoder
+    fn encoder(&self, stream: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => Box::new(brotli::CompressorReader::new(stream, 4096, 5, 22)),
+            Coding::Gzip => Box::new(GzEncoder::new(stream, Compression::default())),
+            // HTTP's deflate is zlib format
+            Coding::Deflate => Box::new(ZlibEncoder::new(stream, Compression::default())),
+        }
+    }
+}
+
+/// Compress body of response with the best coding from `Accept-Encoding`.
+/// Bodies smaller than `threshold`, already compressed ones and partial responses are sent as is.
+pub(super) fn apply(request: &Request, mut response: Response, threshold: usize) -> Response {
+    if !is_compressible(&response) {
+        return response;
+    }
+
+    let length = match response.body() {
+        Body::Bytes(body) => Some(body.len()),
+        Body::Stream(_) | Body::File(_) => response.header("Content-Length").and_then(|e| e.parse().ok()),
+    };
+
+    if length.map(|e| e < threshold).unwrap_or(false) {
+        return response;
+    }
+
+    // Representation depends on request's header, so caches must know it
+    let vary = match response.header("Vary") {
+        Some(vary) if vary.split(',').any(|e| e.trim().eq_ignore_ascii_case("Accept-Encoding") || e.trim() == "*") => vary.to_string(),
+        Some(vary) => format!("{}, Accept-Encoding", vary),
+        None => String::from("Accept-Encoding"),
+    };
+
+    response.set_header("Vary", &vary);
+
+    let coding = match negotiate(&request.headers().get_all("Accept-Encoding")) {
+        Some(e) => e,
+        None => return response,
+    };
+
+    let body = match response.take_body() {
+        Body::Bytes(body) => match encode(coding, body) {
+            Ok(e) => Body::Bytes(e),
+            Err(error) => {
+                println!("e: can't compress response: {}", error);
+                return Response::new(Status::InternalServerError);
+            },
+        },
+        // Length of compressed stream is unknown, so it's sent by chunks
+        Body::Stream(stream) => {
+            response.remove_header("Content-Length");
+            Body::Stream(coding.encoder(stream))
+        },
+        Body::File(file) => {
+            response.remove_header("Content-Length");
+            Body::Stream(coding.encoder(Box::new(file)))
+        },
+    };
+
+    // Compressed body is another representation, so strong tag isn't valid for it
+    if let Some(etag) = response.header("ETag").filter(|e| e.starts_with('"')).map(str::to_string) {
+        response.set_header("ETag", &format!("W/{}", etag));
+    }
+
+    // Ranges are supported for identity body only
+    response.remove_header("Accept-Ranges");
+    response.set_header("Content-Encoding", coding.name());
+    response.set_body(body);
+
+    response
+}
+
+/// Check if response has body which makes sense to compress
+fn is_compressible(response: &Response) -> bool {
+    // Informational, empty, partial and redirect responses are skipped (error pages are compressed)
+    let code = response.status().code();
+
+    if code < 200 || code == 204 || code == 206 || (300..400).contains(&code) {
+        return false;
+    }
+
+    if response.header("Content-Encoding").is_some() || response.header("Content-Range").is_some() {
+        return false;
+    }
+
+    let content_type = match response.header("Content-Type") {
+        Some(e) => e.split(';').next().unwrap_or("").trim().to_ascii_lowercase(),
+        None => return false,
+    };
+
+    // Events must reach client as soon as they are sent
+    if content_type == "text/event-stream" {
+        return false;
+    }
+
+    content_type.starts_with("text/")
+        || content_type.ends_with("+json")
+        || content_type.ends_with("+xml")
+        || ["application/json", "application/javascript", "application/xml", "application/wasm", "image/svg+xml"].contains(&content_type.as_str())
+}
+
+/// Choose coding by `Accept-Encoding` values (`None` if client accepts no supported coding)
+fn negotiate(headers: &[&str]) -> Option<Coding> {
+    let mut preferences = Vec::new();
+
+    for item in headers.iter().flat_map(|e| e.split(',')) {
+        let item = item.to_ascii_lowercase();
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_string();
+
+        // Quality value (`q=0` means that coding isn't acceptable)
+        let quality = parts
+            .filter_map(|e| e.trim().strip_prefix("q="))
+            .find_map(|e| e.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if !name.is_empty() {
+            preferences.push((name, quality));
+        }
+    }
+
+    let quality = |coding: &Coding| {
+        preferences.iter()
+            .find(|(name, _)| name == coding.name() || (*coding == Coding::Gzip && name == "x-gzip"))
+            .or_else(|| preferences.iter().find(|(name, _)| name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+
+    // The first coding with the highest quality wins (order of `ALL` is server's preference)
+    let mut best: Option<(Coding, f32)> = None;
+
+    for coding in Coding::ALL {
+        let quality = quality(coding);
+
+        if quality > 0.0 && best.map(|(_, e)| quality > e).unwrap_or(true) {
+            best = Some((*coding, quality));
+        }
+    }
+
+    best.map(|(coding, _)| coding)
+}
+
+/// Compress body which is known up front
+fn encode(coding: Coding, body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    coding.encoder(Box::new(io::Cursor::new(body))).read_to_end(&mut encoded)?;
+
+    Ok(encoded)
+}
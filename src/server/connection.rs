@@ -0,0 +1,403 @@
+use std::io::{Read, Write, self};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use mio::net::TcpStream;
+
+use super::{Config, EventStream, Request, RequestError};
+use super::deferred::Waiting;
+use super::request::ChunkedProgress;
+use super::websocket::Session;
+
+/// Stage of request processing on connection
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum State {
+    /// Waiting for the next request
+    Reading,
+    /// Request is processing by worker thread
+    Processing,
+    /// Response is sending, then connection is closed
+    Closing,
+    /// Events are sent until connection is closed
+    Streaming,
+}
+
+/// Client connection which is served by event loop
+pub(super) struct Connection {
+    stream: TcpStream,
+    pub(super) peer_addr: SocketAddr,
+    read_buffer: Vec<u8>,
+    /// Decoded part of chunked body of request which is being received
+    chunked: ChunkedProgress,
+    /// Reading was stopped because read buffer is full (the rest of data is left in socket)
+    pub(super) read_paused: bool,
+    write_buffer: Vec<u8>,
+    pub(super) state: State,
+    /// Number of requests which were received on connection
+    pub(super) requests_number: usize,
+    /// Time of the last successful reading or writing
+    pub(super) last_activity: Instant,
+    /// Time when the first byte of current request was received (`None` while the next request is waited for)
+    pub(super) request_start: Option<Instant>,
+    /// Time when headers of current request were received and size of its head
+    pub(super) body_start: Option<(Instant, usize)>,
+    /// Time when data was queued for sending and number of bytes which are sent since then
+    write_start: Option<(Instant, usize)>,
+    /// Time of the last successful writing
+    last_write: Instant,
+    /// Client closed its side of connection
+    pub(super) eof: bool,
+    /// Connection of redirect listener (requests are redirected to HTTPS)
+    pub(super) redirect: bool,
+    /// State of WebSocket (`None` until connection is switched to it)
+    pub(super) websocket: Option<Session>,
+    /// Handle of event stream which is sent to connection
+    pub(super) event_stream: Option<EventStream>,
+    /// Request which waits for deferred response
+    pub(super) waiting: Option<Waiting>,
+    /// Flow of response's parts from worker thread and number of its bytes which were queued since the last release
+    pub(super) flow: Option<(Arc<Flow>, usize)>,
+    /// TLS session (`None` for plain HTTP)
+    #[cfg(feature = "tls")]
+    tls: Option<rustls::ServerConnection>,
+}
+
+impl Connection {
+    pub(super) fn new(stream: TcpStream, peer_addr: SocketAddr) -> Connection {
+        Connection {
+            stream,
+            peer_addr,
+            read_buffer: Vec::new(),
+            chunked: ChunkedProgress::default(),
+            read_paused: false,
+            write_buffer: Vec::new(),
+            state: State::Reading,
+            requests_number: 0,
+            last_activity: Instant::now(),
+            request_start: None,
+            body_start: None,
+            write_start: None,
+            last_write: Instant::now(),
+            eof: false,
+            redirect: false,
+            websocket: None,
+            event_stream: None,
+            waiting: None,
+            flow: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Create connection which is encrypted with TLS
+    #[cfg(feature = "tls")]
+    pub(super) fn with_tls(stream: TcpStream, peer_addr: SocketAddr, tls: rustls::ServerConnection) -> Connection {
+        Connection {
+            tls: Some(tls),
+            ..Connection::new(stream, peer_addr)
+        }
+    }
+
+    pub(super) fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// Received data which isn't parsed yet
+    pub(super) fn read_buffer(&mut self) -> &mut Vec<u8> {
+        &mut self.read_buffer
+    }
+
+    /// Parse the next request from received data (decoding of chunked body is continued from the previous try)
+    pub(super) fn parse_request(&mut self, config: &Config) -> Result<Option<(Request, usize)>, RequestError> {
+        Request::parse(&self.read_buffer, config, self.peer_addr, &mut self.chunked)
+    }
+
+    /// Read available data from socket until read buffer has `limit` bytes
+    pub(super) fn read(&mut self, limit: usize) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return self.read_tls(limit);
+        }
+
+        let mut chunk = [0; 4096];
+
+        loop {
+            self.read_paused = self.read_buffer.len() >= limit;
+
+            if self.read_paused {
+                return Ok(());
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(());
+                },
+                Ok(size) => {
+                    self.read_buffer.extend_from_slice(&chunk[..size]);
+                    self.last_activity = Instant::now();
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Put data to sending queue and try to send it
+    pub(super) fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.write_start.is_none() {
+            self.write_start = Some((Instant::now(), 0));
+            self.last_write = Instant::now();
+        }
+
+        self.write_buffer.extend_from_slice(data);
+        self.write()
+    }
+
+    /// Send as much queued data as socket accepts
+    pub(super) fn write(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        let result = if self.tls.is_some() { self.write_tls() } else { self.write_plain() };
+
+        #[cfg(not(feature = "tls"))]
+        let result = self.write_plain();
+
+        if self.is_flushed() {
+            self.write_start = None;
+        }
+
+        result
+    }
+
+    /// Client doesn't read queued data in time (see `Config::write_timeout`)
+    pub(super) fn is_write_expired(&self, timeout: Duration, min_transfer_rate: usize) -> bool {
+        match self.write_start {
+            Some((start, written)) => self.last_write.elapsed() >= timeout
+                || start.elapsed() >= extend(timeout, written, min_transfer_rate),
+            None => false,
+        }
+    }
+
+    /// Client doesn't send body of current request in time (see `Config::body_timeout`)
+    pub(super) fn is_body_expired(&self, timeout: Duration, min_transfer_rate: usize) -> bool {
+        match self.body_start {
+            Some((start, head_length)) => {
+                let received = self.read_buffer.len().saturating_sub(head_length);
+
+                start.elapsed() >= extend(timeout, received, min_transfer_rate)
+            },
+            None => false,
+        }
+    }
+
+    /// Count sent bytes
+    fn wrote(&mut self, size: usize) {
+        self.last_activity = Instant::now();
+        self.last_write = Instant::now();
+
+        if let Some((_, written)) = &mut self.write_start {
+            *written += size;
+        }
+    }
+
+    /// Send queued data to plain socket
+    fn write_plain(&mut self) -> io::Result<()> {
+        let mut written = 0;
+
+        while written < self.write_buffer.len() {
+            match self.stream.write(&self.write_buffer[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(size) => {
+                    written += size;
+                    self.wrote(size);
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        self.write_buffer.drain(..written);
+
+        Ok(())
+    }
+
+    /// Size of data which is queued for sending
+    pub(super) fn queued(&self) -> usize {
+        self.write_buffer.len()
+    }
+
+    /// All queued data was sent
+    pub(super) fn is_flushed(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return self.write_buffer.is_empty() && !tls.wants_write();
+        }
+
+        self.write_buffer.is_empty()
+    }
+
+    /// Close sending side of connection
+    pub(super) fn shutdown(&mut self) {
+        // Client is notified that TLS session is closed
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+            let _ = self.write_tls();
+        }
+
+        // Client could close connection already, so error is not interesting
+        let _ = self.stream.shutdown(Shutdown::Write);
+    }
+
+    /// Read and decrypt available data from socket until read buffer has `limit` bytes
+    /// (handshake messages are answered right away)
+    #[cfg(feature = "tls")]
+    fn read_tls(&mut self, limit: usize) -> io::Result<()> {
+        let tls = match &mut self.tls {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let mut chunk = [0; 4096];
+
+        loop {
+            self.read_paused = self.read_buffer.len() >= limit;
+
+            if self.read_paused {
+                break;
+            }
+
+            match tls.read_tls(&mut self.stream) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                },
+                Ok(_) => {
+                    self.last_activity = Instant::now();
+
+                    if let Err(error) = tls.process_new_packets() {
+                        // Send alert to client before closing
+                        let _ = self.write_tls();
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                    }
+
+                    // Move decrypted data to read buffer
+                    loop {
+                        match tls.reader().read(&mut chunk) {
+                            // Client closed TLS session
+                            Ok(0) => {
+                                self.eof = true;
+                                break;
+                            },
+                            Ok(size) => self.read_buffer.extend_from_slice(&chunk[..size]),
+                            Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(error) => return Err(error),
+                        }
+                    }
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        self.write_tls()
+    }
+
+    /// Encrypt queued data and send as much of it as socket accepts
+    #[cfg(feature = "tls")]
+    fn write_tls(&mut self) -> io::Result<()> {
+        loop {
+            let tls = match &mut self.tls {
+                Some(e) => e,
+                None => return Ok(()),
+            };
+
+            // TLS session buffers limited amount of data
+            if !self.write_buffer.is_empty() {
+                let accepted = tls.writer().write(&self.write_buffer)?;
+                self.write_buffer.drain(..accepted);
+            }
+
+            if !tls.wants_write() {
+                return Ok(());
+            }
+
+            let size = match tls.write_tls(&mut self.stream) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(size) => size,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            };
+
+            self.wrote(size);
+        }
+    }
+}
+
+/// Amount of response's data which worker thread passed to event loop and which isn't sent yet.
+/// Worker thread waits while there is too much of it, so body isn't kept in memory when client reads it slowly.
+pub(super) struct Flow {
+    /// Number of passed bytes which weren't released yet and `true` if connection is closed
+    state: Mutex<(usize, bool)>,
+    released: Condvar,
+}
+
+impl Flow {
+    pub(super) fn new() -> Flow {
+        Flow {
+            state: Mutex::new((0, false)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until `size` bytes can be passed with no more than `limit` unsent bytes (error means that connection is closed)
+    pub(super) fn acquire(&self, size: usize, limit: usize) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        // Part which is bigger than limit is passed when everything else is sent
+        while !state.1 && state.0 > 0 && state.0 + size > limit {
+            state = self.released.wait(state).unwrap();
+        }
+
+        if state.1 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        state.0 += size;
+
+        Ok(())
+    }
+
+    /// Let worker thread pass the next parts instead of sent ones
+    pub(super) fn release(&self, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = state.0.saturating_sub(size);
+
+        self.released.notify_all();
+    }
+
+    /// Connection is closed, so the rest of response isn't needed
+    pub(super) fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+
+        self.released.notify_all();
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().1
+    }
+}
+
+/// Timeout which is extended by one second for every `min_transfer_rate` transferred bytes
+fn extend(timeout: Duration, transferred: usize, min_transfer_rate: usize) -> Duration {
+    if min_transfer_rate == 0 {
+        return timeout;
+    }
+
+    timeout.saturating_add(Duration::from_secs_f64(transferred as f64 / min_transfer_rate as f64))
+}
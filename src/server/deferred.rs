@@ -0,0 +1,96 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+use mio::{Token, Waker};
+
+use super::{Impulse, Response};
+
+/// Request which waits in event loop for deferred response
+pub(super) struct Waiting {
+    pub(super) handle: Deferred,
+    pub(super) deadline: Instant,
+    /// Response of handler which is sent at deadline
+    pub(super) response: Vec<u8>,
+    /// Connection can be used for the next request after response of handler
+    pub(super) keep_alive: bool,
+}
+
+/// Handle of request which is answered later from any thread (it can be cloned and kept, e.g. by subscriber of events)
+#[derive(Clone)]
+pub struct Deferred {
+    token: Token,
+    controller_tx: Sender<Impulse>,
+    waker: Arc<Waker>,
+    /// Response isn't sent yet and connection isn't closed
+    open: Arc<AtomicBool>,
+    keep_alive: bool,
+    head_request: bool,
+    chunked: bool,
+    /// Value of `Strict-Transport-Security` header
+    hsts: Option<String>,
+}
+
+impl Deferred {
+    pub(super) fn new(token: Token, controller_tx: Sender<Impulse>, waker: Arc<Waker>, keep_alive: bool, head_request: bool, chunked: bool, hsts: Option<String>) -> Deferred {
+        Deferred {
+            token,
+            controller_tx,
+            waker,
+            open: Arc::new(AtomicBool::new(true)),
+            keep_alive,
+            head_request,
+            chunked,
+            hsts,
+        }
+    }
+
+    /// Send response instead of the one of handler (error means that request was answered already or connection is closed).
+    /// Middlewares and compression aren't applied to it (`Strict-Transport-Security` is added), and stream body is read right away.
+    pub fn respond(&self, mut response: Response) -> io::Result<()> {
+        if !self.open.swap(false, Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        if let Some(hsts) = &self.hsts {
+            response.set_header("Strict-Transport-Security", hsts);
+        }
+
+        let (data, keep_alive) = serialize(response, self.keep_alive, self.head_request, self.chunked);
+
+        self.impulse(Impulse::Response(self.token, data, keep_alive))
+    }
+
+    /// Request isn't answered yet
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Mark request as answered (`false` if it was answered already)
+    pub(super) fn close(&self) -> bool {
+        self.open.swap(false, Ordering::SeqCst)
+    }
+
+    /// Send impulse to event loop and wake it up
+    pub(super) fn impulse(&self, impulse: Impulse) -> io::Result<()> {
+        self.controller_tx.send(impulse)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.waker.wake()
+    }
+}
+
+/// Serialize response to bytes and check if connection can be kept after it
+/// (connection is closed with no response if body can't be read)
+pub(super) fn serialize(response: Response, keep_alive: bool, head_request: bool, chunked: bool) -> (Vec<u8>, bool) {
+    let mut data = Vec::new();
+
+    match response.write_to(&mut data, keep_alive, head_request, chunked) {
+        Ok(keep_alive) => (data, keep_alive),
+        Err(error) => {
+            println!("e: problems with writing of response: {}", error);
+            (Vec::new(), false)
+        },
+    }
+}
@@ -0,0 +1,1260 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{Write, self};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
+
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::TcpListener;
+
+use super::{Config, Deferred, EventStream, Handler, Impulse, ShutdownReport, Job, Redirect, Request, RequestError, Response, RouteOptions, Status, WebSocket, WebSocketHandler};
+use super::compression;
+use super::deferred::{self, Waiting};
+use super::connection::{Connection, Flow, State};
+use super::middleware::{Chain, Next};
+use super::event_stream;
+use super::pool::Pool;
+use super::range;
+use super::request;
+use super::response::Upgrade;
+use super::router::Router;
+use super::websocket::{self, Event, Received, Session};
+
+/// Token of listening socket
+const LISTENER: Token = Token(0);
+
+/// Token of waker which signals about new impulses
+pub(super) const WAKER: Token = Token(1);
+
+/// Token of listening socket which redirects requests to HTTPS
+const REDIRECT_LISTENER: Token = Token(2);
+
+/// Size of response's part which is passed from worker thread to event loop
+const PART_SIZE: usize = 64 * 1024;
+
+/// Maximal size of response's parts which are passed by worker thread and aren't sent yet
+const MAX_UNSENT_SIZE: usize = 4 * PART_SIZE;
+
+/// Work which is waiting for worker thread
+enum Task {
+    /// Request of HTTP connection
+    Request {
+        token: Token,
+        request: Request,
+        keep_alive: bool,
+        /// Request was received by redirect listener
+        redirect: bool,
+    },
+    /// Event of WebSocket connection
+    WebSocket(WebSocket, Arc<dyn WebSocketHandler>, Event),
+}
+
+/// Readiness loop which accepts connections, reads requests and writes responses.
+/// Handlers are run by worker threads, so idle connections cost no threads.
+pub(super) struct EventLoop {
+    poll: Poll,
+    /// Listener of server (`None` after shutdown is started)
+    listener: Option<TcpListener>,
+    redirect_listener: Option<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    /// Tokens are never reused, so late response can't get to another connection
+    next_token: usize,
+    config: Arc<Config>,
+    /// Settings which are used by worker threads (they are replaced on reload)
+    shared_config: Arc<RwLock<Arc<Config>>>,
+    handlers: Arc<RwLock<Router>>,
+    middlewares: Arc<RwLock<Chain>>,
+    error_handlers: Arc<RwLock<HashMap<RequestError, Job>>>,
+    controller_rx: Receiver<Impulse>,
+    pool: Pool<Task>,
+    /// Deadline of graceful shutdown (`None` until it's started)
+    deadline: Option<Instant>,
+    report: ShutdownReport,
+    /// Settings of TLS connections (`None` for plain HTTP)
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl EventLoop {
+    pub(super) fn new(poll: Poll, mut listener: TcpListener, mut redirect_listener: Option<TcpListener>, config: Config, controller_tx: Sender<Impulse>, controller_rx: Receiver<Impulse>, waker: Arc<Waker>) -> io::Result<EventLoop> {
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        if let Some(redirect_listener) = &mut redirect_listener {
+            poll.registry().register(redirect_listener, REDIRECT_LISTENER, Interest::READABLE)?;
+        }
+
+        #[cfg(feature = "tls")]
+        let tls = match &config.tls {
+            Some(certificates) => Some(certificates.server_config()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?),
+            None => None,
+        };
+
+        let config = Arc::new(config);
+        let shared_config = Arc::new(RwLock::new(Arc::clone(&config)));
+        let handlers = Arc::new(RwLock::new(Router::new()));
+        let middlewares = Arc::new(RwLock::new(Chain::new()));
+        let error_handlers: Arc<RwLock<HashMap<RequestError, Job>>> = Arc::new(RwLock::new(HashMap::new()));
+
+        // Start worker threads
+        let pool = {
+            let shared_config = Arc::clone(&shared_config);
+            let handlers = Arc::clone(&handlers);
+            let middlewares = Arc::clone(&middlewares);
+            let error_handlers = Arc::clone(&error_handlers);
+
+            Pool::new(config.max_threads_number, config.queue_size, move |task: Task| match task {
+                Task::Request { token, mut request, keep_alive, redirect } => {
+                    let config = Arc::clone(&shared_config.read().unwrap());
+                    let head_request = request.method() == "HEAD";
+                    let chunked = request.version() == "HTTP/1.1";
+                    let mut response = match &config.redirect {
+                        Some(listener) if redirect => process_redirect(&request, listener, &error_handlers),
+                        _ => process_request(&mut request, &config, &handlers, &middlewares, &error_handlers),
+                    };
+
+                    match response.take_upgrade() {
+                        // Connection is switched to WebSocket after response of handshake
+                        Some(Upgrade::WebSocket(handler)) => {
+                            let socket = WebSocket::new(token, controller_tx.clone(), Arc::clone(&waker));
+
+                            // Event loop is stopped, so there is nobody to serve connection
+                            let _ = socket.impulse(Impulse::Upgrade(token, serialize(response, true, false), Arc::clone(&handler), socket.clone()));
+
+                            if catch_panic("on_open of WebSocket", || handler.on_open(&socket)).is_none() {
+                                let _ = socket.close(websocket::INTERNAL_ERROR, "");
+                            }
+
+                            let _ = socket.impulse(Impulse::Processed(token));
+                            return;
+                        },
+                        // Connection is used for events after headers (the end of body is closing of connection)
+                        Some(Upgrade::EventStream(on_open)) if !head_request => {
+                            let stream = EventStream::new(token, controller_tx.clone(), Arc::clone(&waker));
+                            let mut head = Vec::new();
+
+                            if let Err(error) = response.with_stream(io::empty()).write_to(&mut head, false, false, false) {
+                                println!("e: problems with writing of response: {}", error);
+                            }
+
+                            let _ = stream.impulse(Impulse::Stream(token, head, stream.clone()));
+
+                            let handle = stream.clone();
+
+                            if catch_panic("on_open of event stream", move || on_open(handle)).is_none() {
+                                let _ = stream.close();
+                            }
+
+                            return;
+                        },
+                        // Request waits in event loop for response of handle, so worker thread is free
+                        Some(Upgrade::Deferred(timeout, on_open)) => {
+                            let deferred = Deferred::new(token, controller_tx.clone(), Arc::clone(&waker), keep_alive, head_request, chunked, hsts(&config).cloned());
+                            let (data, keep_alive) = deferred::serialize(response, keep_alive, head_request, chunked);
+
+                            let _ = deferred.impulse(Impulse::Defer(token, Waiting {
+                                handle: deferred.clone(),
+                                deadline: Instant::now() + timeout,
+                                response: data,
+                                keep_alive,
+                            }));
+
+                            let handle = deferred.clone();
+
+                            if catch_panic("on_open of deferred response", move || on_open(handle)).is_none() {
+                                let _ = deferred.respond(error_response(&error_handlers.read().unwrap(), RequestError::InternalServerError, &request));
+                            }
+
+                            return;
+                        },
+                        _ => (),
+                    }
+
+                    // Send response back to event loop (streams are sent by parts)
+                    let mut writer = ResponseWriter {
+                        token,
+                        controller_tx: &controller_tx,
+                        waker: &waker,
+                        buffer: Vec::new(),
+                        flow: Arc::new(Flow::new()),
+                    };
+
+                    // Client can be disconnected and stream of body can be panicked, then connection is closed
+                    let keep_alive = match catch_panic("stream of response", || response.write_to(&mut writer, keep_alive, head_request, chunked)) {
+                        Some(Ok(keep_alive)) => keep_alive,
+                        Some(Err(error)) => {
+                            println!("e: problems with writing of response: {}", error);
+                            false
+                        },
+                        None => false,
+                    };
+
+                    writer.finish(keep_alive);
+                },
+                Task::WebSocket(socket, handler, Event::Message(message)) => {
+                    if catch_panic("on_message of WebSocket", || handler.on_message(&socket, message)).is_none() {
+                        let _ = socket.close(websocket::INTERNAL_ERROR, "");
+                    }
+
+                    let _ = socket.impulse(Impulse::Processed(socket.token()));
+                },
+                Task::WebSocket(socket, handler, Event::Close(code, reason)) => {
+                    catch_panic("on_close of WebSocket", || handler.on_close(&socket, code, &reason));
+                },
+            })
+        };
+
+        Ok(EventLoop {
+            poll,
+            listener: Some(listener),
+            redirect_listener,
+            connections: HashMap::new(),
+            next_token: REDIRECT_LISTENER.0 + 1,
+            config,
+            shared_config,
+            handlers,
+            middlewares,
+            error_handlers,
+            controller_rx,
+            pool,
+            deadline: None,
+            report: ShutdownReport::default(),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    /// Serve connections until shutdown and return report of it
+    pub(super) fn run(mut self) -> ShutdownReport {
+        println!("i: main server thread is started");
+
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            if let Err(error) = self.poll.poll(&mut events, Some(super::QUEUE_CHECK_INTERVAL)) {
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                println!("e: main server thread is stopped: {}", error);
+                break;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(LISTENER),
+                    REDIRECT_LISTENER => self.accept(REDIRECT_LISTENER),
+                    WAKER => (),
+                    token => {
+                        if event.is_readable() || event.is_read_closed() || event.is_error() {
+                            self.read(token);
+                        }
+
+                        if event.is_writable() {
+                            self.write(token);
+                        }
+                    },
+                }
+            }
+
+            self.process_impulses();
+
+            self.check_timeouts();
+
+            if let Some(deadline) = self.deadline {
+                if self.connections.is_empty() || Instant::now() >= deadline {
+                    break;
+                }
+            }
+        }
+
+        // The rest of connections are cut off
+        let tokens = self.connections.keys().copied().collect::<Vec<Token>>();
+
+        for token in tokens {
+            let stream = self.connections.get(&token)
+                .map(|e| e.websocket.is_some() || e.state == State::Streaming)
+                .unwrap_or(false);
+
+            if stream {
+                self.report.cut_streams += 1;
+            } else {
+                self.report.cut_requests += 1;
+            }
+
+            self.close(token);
+        }
+
+        self.report.drained = self.report.drained.saturating_sub(self.report.cut_requests + self.report.cut_streams);
+        self.report.detached_threads = self.pool.join(self.deadline.unwrap_or_else(Instant::now));
+
+        println!("i: main server thread is stopped");
+
+        self.report
+    }
+
+    /// Accept all pending connections of listener
+    fn accept(&mut self, listener: Token) {
+        let redirect = listener == REDIRECT_LISTENER;
+
+        loop {
+            let listener = if redirect { &self.redirect_listener } else { &self.listener };
+
+            let accepted = match listener {
+                Some(e) => e.accept(),
+                None => return,
+            };
+
+            match accepted {
+                Ok((mut stream, peer_addr)) => {
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+
+                    if let Err(error) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                        println!("e: can't register connection: {}", error);
+                        continue;
+                    }
+
+                    // Redirect listener always serves plain HTTP
+                    #[cfg(feature = "tls")]
+                    let mut connection = match &self.tls {
+                        Some(config) if !redirect => match rustls::ServerConnection::new(Arc::clone(config)) {
+                            Ok(tls) => Connection::with_tls(stream, peer_addr, tls),
+                            Err(error) => {
+                                println!("e: can't start TLS session: {}", error);
+                                continue;
+                            },
+                        },
+                        _ => Connection::new(stream, peer_addr),
+                    };
+
+                    #[cfg(not(feature = "tls"))]
+                    let mut connection = Connection::new(stream, peer_addr);
+
+                    connection.redirect = redirect;
+                    self.connections.insert(token, connection);
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    println!("e: can't accept connection: {}", error);
+                    break;
+                },
+            }
+        }
+    }
+
+    /// Process impulses from server and worker threads
+    fn process_impulses(&mut self) {
+        while let Ok(impulse) = self.controller_rx.try_recv() {
+            match impulse {
+                // Process handler (endpoint)
+                Impulse::Handler(method, pattern, closure, options) => {
+                    println!("i: got Handler impulse");
+
+                    self.handlers.write().unwrap().insert(method, pattern, closure, options);
+                },
+                // Process middleware
+                Impulse::Middleware(prefix, closure) => {
+                    println!("i: got Middleware impulse");
+
+                    self.middlewares.write().unwrap().insert(&prefix, closure);
+                },
+                // Process error handler (like 400, 404 and 503)
+                Impulse::ErrorHandler(error, closure) => {
+                    println!("i: got ErrorHandler impulse");
+
+                    self.error_handlers.write().unwrap().insert(error, closure);
+                },
+                // Process part of response which is being sent by worker thread
+                Impulse::Data(token, data, flow) => {
+                    let connection = match self.connections.get_mut(&token) {
+                        Some(e) => e,
+                        None => {
+                            flow.close();
+                            continue;
+                        },
+                    };
+
+                    match &mut connection.flow {
+                        Some((current, queued)) if Arc::ptr_eq(current, &flow) => *queued += data.len(),
+                        _ => connection.flow = Some((flow, data.len())),
+                    }
+
+                    self.send(token, &data);
+                    self.release(token);
+                },
+                // Process response of worker thread
+                Impulse::Response(token, response, keep_alive) => {
+                    self.respond(token, &response, keep_alive);
+                },
+                // Process switching of connection to WebSocket
+                Impulse::Upgrade(token, response, handler, socket) => {
+                    self.upgrade(token, &response, handler, socket);
+                },
+                // Process switching of connection to event stream
+                Impulse::Stream(token, response, stream) => {
+                    self.stream(token, &response, stream);
+                },
+                // Process request which waits for deferred response
+                Impulse::Defer(token, waiting) => match self.connections.get_mut(&token) {
+                    Some(connection) => {
+                        connection.waiting = Some(waiting);
+
+                        // Nothing is waited for while shutdown
+                        if self.deadline.is_some() {
+                            self.answer_waiting(token);
+                        }
+                    },
+                    None => {
+                        waiting.handle.close();
+                    },
+                },
+                // Process frame which is sent to WebSocket connection or event stream
+                Impulse::Frame(token, frame) => {
+                    let open = match self.connections.get(&token) {
+                        Some(connection) => match &connection.websocket {
+                            Some(session) => !session.close_sent,
+                            None => connection.state == State::Streaming,
+                        },
+                        None => false,
+                    };
+
+                    if open {
+                        self.send(token, &frame);
+                    }
+                },
+                // Process finishing of event stream
+                Impulse::End(token) => {
+                    self.finish(token);
+                },
+                // Process closing of WebSocket connection by server
+                Impulse::Close(token, code, reason) => {
+                    self.close_websocket(token, code, &reason);
+                },
+                // Process end of WebSocket event's processing
+                Impulse::Processed(token) => {
+                    self.processed(token);
+                },
+                // Process new settings
+                Impulse::Reload(config) => {
+                    println!("i: got Reload impulse");
+                    self.reload(*config);
+                },
+                // Process shutdown (stop accepting, then loop is broken when connections are drained)
+                Impulse::Shutdown(deadline) => {
+                    println!("i: got Shutdown impulse");
+                    self.shutdown(deadline);
+                },
+            }
+        }
+    }
+
+    /// Read data from connection and process received requests
+    fn read(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if let Err(error) = connection.read(read_limit(&self.config)) {
+            println!("e: problems with reading from stream: {}", error);
+            self.close(token);
+            return;
+        }
+
+        // Any data from client shows that it is alive
+        if let Some(session) = &mut connection.websocket {
+            session.ping_sent = None;
+        }
+
+        self.advance(token);
+    }
+
+    /// Send queued data of connection
+    fn write(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if let Err(error) = connection.write() {
+            println!("e: problems with writing to stream: {}", error);
+            self.close(token);
+            return;
+        }
+
+        if connection.state == State::Closing && connection.is_flushed() {
+            self.close(token);
+            return;
+        }
+
+        self.release(token);
+    }
+
+    /// Let worker thread pass the next parts of response when the previous ones are almost sent
+    fn release(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if connection.queued() >= PART_SIZE {
+            return;
+        }
+
+        if let Some((flow, queued)) = &mut connection.flow {
+            flow.release(*queued);
+            *queued = 0;
+        }
+    }
+
+    /// Parse the next request of connection and pass it to worker thread
+    fn advance(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if connection.state != State::Reading && connection.state != State::Streaming {
+            return;
+        }
+
+        // Data which was left in socket while request was processed is read now
+        if connection.read_paused {
+            if let Err(error) = connection.read(read_limit(&self.config)) {
+                println!("e: problems with reading from stream: {}", error);
+                self.close(token);
+                return;
+            }
+        }
+
+        if connection.websocket.is_some() {
+            self.advance_websocket(token);
+            return;
+        }
+
+        // Client of event stream only waits for events
+        if connection.state == State::Streaming {
+            connection.read_buffer().clear();
+
+            while connection.read_paused {
+                if let Err(error) = connection.read(read_limit(&self.config)) {
+                    println!("e: problems with reading from stream: {}", error);
+                    self.close(token);
+                    return;
+                }
+
+                connection.read_buffer().clear();
+            }
+
+            if connection.eof {
+                self.close(token);
+            }
+
+            return;
+        }
+
+        let peer_addr = connection.peer_addr;
+        let redirect = connection.redirect;
+
+        // Request which doesn't fit into read buffer can't be received (e.g. body with too many small chunks)
+        let parsed = match connection.parse_request(&self.config) {
+            Ok(None) if connection.read_paused => Err(RequestError::PayloadTooLarge),
+            parsed => parsed,
+        };
+
+        match parsed {
+            Ok(Some((request, consumed))) => {
+                connection.read_buffer().drain(..consumed);
+                connection.requests_number += 1;
+                connection.state = State::Processing;
+
+                // The next pipelined request is started already
+                connection.request_start = if connection.read_buffer().is_empty() { None } else { Some(Instant::now()) };
+                connection.body_start = None;
+
+                println!("i: connect {} {}", request.method(), request.path());
+
+                let keep_alive = request.keep_alive()
+                    && connection.requests_number < self.config.max_requests_per_connection;
+
+                // Put request to queue of worker threads
+                if let Err(task) = self.pool.execute(Task::Request { token, request, keep_alive, redirect }) {
+                    println!("i: max threads number was achieved and queue is full");
+
+                    self.reject(task);
+                }
+            },
+            Ok(None) => {
+                // Times of request's parts are remembered to check timeouts
+                if connection.request_start.is_none() && !connection.read_buffer().is_empty() {
+                    connection.request_start = Some(Instant::now());
+                }
+
+                if connection.body_start.is_none() {
+                    if let Some((_, body_start)) = request::find_head_end(connection.read_buffer()) {
+                        connection.body_start = Some((Instant::now(), body_start));
+                    }
+                }
+
+                if connection.eof {
+                    if !connection.read_buffer().is_empty() {
+                        println!("e: connection was closed before request was received");
+                    }
+
+                    // Close connection after the last response is sent
+                    if connection.is_flushed() {
+                        self.close(token);
+                    } else {
+                        connection.state = State::Closing;
+                    }
+                }
+            },
+            Err(error) => {
+                println!("i: incorrect request: {:?}", error);
+
+                // 400, 413 or 431 error (the rest of connection can't be parsed)
+                self.respond_error(token, error, &Request::empty(peer_addr), redirect);
+            },
+        }
+    }
+
+    /// Process received frames of WebSocket connection until the next message is passed to worker thread
+    fn advance_websocket(&mut self, token: Token) {
+        loop {
+            let connection = match self.connections.get_mut(&token) {
+                Some(e) => e,
+                None => return,
+            };
+
+            if connection.state != State::Reading {
+                return;
+            }
+
+            let received = match websocket::parse_frame(connection.read_buffer(), self.config.max_frame_size) {
+                Ok(Some((frame, consumed))) => {
+                    connection.read_buffer().drain(..consumed);
+
+                    match &mut connection.websocket {
+                        Some(session) => session.receive(frame, self.config.max_body_size),
+                        None => return,
+                    }
+                },
+                Ok(None) => {
+                    if connection.eof {
+                        if connection.is_flushed() {
+                            self.close(token);
+                        } else {
+                            connection.state = State::Closing;
+                        }
+                    }
+
+                    return;
+                },
+                Err(code) => Err(code),
+            };
+
+            match received {
+                Ok(None) => continue,
+                Ok(Some(Received::Message(message))) => {
+                    let session = match &connection.websocket {
+                        Some(e) => e,
+                        None => return,
+                    };
+
+                    let task = Task::WebSocket(session.socket.clone(), Arc::clone(&session.handler), Event::Message(message));
+                    connection.state = State::Processing;
+
+                    if let Err(task) = self.pool.execute(task) {
+                        println!("i: max threads number was achieved and queue is full");
+
+                        self.reject(task);
+                    }
+
+                    return;
+                },
+                Ok(Some(Received::Ping(payload))) => self.send(token, &websocket::encode_frame(websocket::PONG, &payload)),
+                Ok(Some(Received::Close(code, reason))) => {
+                    if let Some(session) = &mut connection.websocket {
+                        session.close_status = Some((code, reason.clone()));
+                    }
+
+                    // Close frame is answered, then server closes connection
+                    self.close_websocket(token, code, &reason);
+                    self.finish(token);
+                    return;
+                },
+                Err(code) => {
+                    println!("e: WebSocket connection is failed with code {}", code);
+
+                    self.close_websocket(token, code, "");
+                    self.finish(token);
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Send response of handshake and switch connection to WebSocket
+    fn upgrade(&mut self, token: Token, response: &[u8], handler: Arc<dyn WebSocketHandler>, socket: WebSocket) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => {
+                socket.set_closed();
+                return;
+            },
+        };
+
+        // Connection is processing until `on_open` is finished
+        connection.websocket = Some(Session::new(socket, handler));
+
+        self.send(token, response);
+
+        if self.deadline.is_some() {
+            self.close_websocket(token, websocket::GOING_AWAY, "Going Away");
+        }
+    }
+
+    /// Send headers of event stream and keep connection for events
+    fn stream(&mut self, token: Token, response: &[u8], stream: EventStream) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => {
+                stream.set_closed();
+                return;
+            },
+        };
+
+        connection.event_stream = Some(stream);
+        connection.state = State::Streaming;
+
+        self.send(token, response);
+
+        if self.deadline.is_some() {
+            self.finish(token);
+        }
+    }
+
+    /// Replace settings (settings of listeners, worker threads and TLS are kept)
+    fn reload(&mut self, mut config: Config) {
+        config.max_threads_number = self.config.max_threads_number;
+        config.queue_size = self.config.queue_size;
+        config.redirect = self.config.redirect.clone();
+
+        #[cfg(feature = "tls")]
+        {
+            config.tls = self.config.tls.clone();
+        }
+
+        let config = Arc::new(config);
+
+        *self.shared_config.write().unwrap() = Arc::clone(&config);
+        self.config = config;
+    }
+
+    /// Start graceful shutdown: close listeners and idle connections, finish streams
+    fn shutdown(&mut self, deadline: Instant) {
+        if self.deadline.is_some() {
+            return;
+        }
+
+        self.deadline = Some(deadline);
+        self.report.drained = self.connections.len();
+
+        for listener in [self.listener.take(), self.redirect_listener.take()].iter_mut().flatten() {
+            if let Err(error) = self.poll.registry().deregister(listener) {
+                println!("e: can't deregister listener: {}", error);
+            }
+        }
+
+        let tokens = self.connections.keys().copied().collect::<Vec<Token>>();
+
+        for token in tokens {
+            let connection = match self.connections.get_mut(&token) {
+                Some(e) => e,
+                None => continue,
+            };
+
+            if connection.websocket.is_some() {
+                self.close_websocket(token, websocket::GOING_AWAY, "Going Away");
+            } else if connection.state == State::Streaming {
+                self.finish(token);
+            } else if connection.waiting.is_some() {
+                self.answer_waiting(token);
+            } else if connection.state == State::Reading && connection.read_buffer().is_empty() {
+                // Persistent connection is waiting for the next request
+                self.close(token);
+            }
+        }
+    }
+
+    /// Send response of handler to request which waits for deferred response
+    fn answer_waiting(&mut self, token: Token) {
+        let waiting = match self.connections.get_mut(&token).and_then(|e| e.waiting.take()) {
+            Some(e) => e,
+            None => return,
+        };
+
+        // Handle could send its response already (then it's in queue of impulses)
+        if waiting.handle.close() {
+            self.respond(token, &waiting.response, waiting.keep_alive);
+        }
+    }
+
+    /// Send close frame to WebSocket connection if it wasn't sent yet
+    fn close_websocket(&mut self, token: Token, code: u16, reason: &str) {
+        let session = match self.connections.get_mut(&token).and_then(|e| e.websocket.as_mut()) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if session.close_status.is_none() {
+            session.close_status = Some((code, reason.to_string()));
+        }
+
+        if session.close_sent {
+            return;
+        }
+
+        session.close_sent = true;
+        session.socket.set_closed();
+
+        self.send(token, &websocket::close_frame(code, reason));
+    }
+
+    /// Continue reading of WebSocket connection after its event was processed
+    fn processed(&mut self, token: Token) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            if connection.state == State::Processing {
+                connection.state = State::Reading;
+                self.advance(token);
+            }
+        }
+    }
+
+    /// Close connection after all queued data is sent
+    fn finish(&mut self, token: Token) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            connection.state = State::Closing;
+
+            if connection.is_flushed() {
+                self.close(token);
+            }
+        }
+    }
+
+    /// Send part of response to connection
+    fn send(&mut self, token: Token, data: &[u8]) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            if let Err(error) = connection.send(data) {
+                println!("e: problems with writing to stream: {}", error);
+                self.close(token);
+            }
+        }
+    }
+
+    /// Send response to connection and continue with the next request
+    fn respond(&mut self, token: Token, response: &[u8], keep_alive: bool) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(e) => e,
+            None => return,
+        };
+
+        // No more requests are read while shutdown
+        let keep_alive = keep_alive && self.deadline.is_none();
+
+        connection.waiting = None;
+        connection.state = if keep_alive { State::Reading } else { State::Closing };
+
+        if let Err(error) = connection.send(response) {
+            println!("e: problems with writing to stream: {}", error);
+            self.close(token);
+            return;
+        }
+
+        if keep_alive {
+            // Process pipelined request
+            self.advance(token);
+        } else if connection.is_flushed() {
+            self.close(token);
+        }
+    }
+
+    /// Send response of error handler for error which is found by event loop and close connection
+    fn respond_error(&mut self, token: Token, error: RequestError, request: &Request, redirect: bool) {
+        let mut response = error_response(&self.error_handlers.read().unwrap(), error, request);
+
+        // Redirect listener serves plain HTTP
+        if let Some(hsts) = hsts(&self.config).filter(|_| !redirect) {
+            response.set_header("Strict-Transport-Security", hsts);
+        }
+
+        self.respond(token, &serialize(response, false, request.method() == "HEAD"), false);
+    }
+
+    /// Answer 503 to request which can't be served now (WebSocket connection is closed with 1013)
+    fn reject(&mut self, task: Task) {
+        match task {
+            Task::Request { token, request, redirect, .. } => {
+                self.respond_error(token, RequestError::ServiceUnavailable, &request, redirect);
+            },
+            Task::WebSocket(socket, _, Event::Message(_)) => {
+                self.close_websocket(socket.token(), websocket::TRY_AGAIN_LATER, "Try Again Later");
+
+                // Close frame of client is waited for
+                self.processed(socket.token());
+            },
+            Task::WebSocket(_, _, Event::Close(..)) => {
+                println!("e: closing of WebSocket connection wasn't processed");
+            },
+        }
+    }
+
+    /// Reject requests waiting in queue too long and close idle connections
+    fn check_timeouts(&mut self) {
+        for task in self.pool.expire(self.config.queue_timeout) {
+            println!("i: request waited in queue too long");
+
+            self.reject(task);
+        }
+
+        // Idle connections wait for the next request (partial requests are checked with header and body timeouts)
+        let mut expired = self.connections.iter()
+            .filter(|(_, e)| e.websocket.is_none() && e.state == State::Reading && e.request_start.is_none() && e.is_flushed() && e.last_activity.elapsed() >= self.config.keep_alive_timeout)
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+
+        // Slow clients: requests which aren't received in time get 408, connections which don't read data are closed
+        let mut timed_out = Vec::new();
+
+        for (token, connection) in self.connections.iter() {
+            if connection.is_write_expired(self.config.write_timeout, self.config.min_transfer_rate) {
+                expired.push(*token);
+            } else if connection.websocket.is_none() && connection.state == State::Reading {
+                let headers_expired = connection.body_start.is_none() && connection.request_start
+                    .map(|e| e.elapsed() >= self.config.header_timeout)
+                    .unwrap_or(false);
+
+                if headers_expired || connection.is_body_expired(self.config.body_timeout, self.config.min_transfer_rate) {
+                    timed_out.push((*token, connection.peer_addr, connection.redirect));
+                }
+            }
+        }
+
+        for (token, peer_addr, redirect) in timed_out {
+            println!("i: request wasn't received in time");
+
+            self.respond_error(token, RequestError::RequestTimeout, &Request::empty(peer_addr), redirect);
+        }
+
+        // Requests which didn't get deferred response in time get response of handler
+        let waited = self.connections.iter()
+            .filter(|(_, e)| e.waiting.as_ref().map(|e| Instant::now() >= e.deadline).unwrap_or(false))
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+
+        for token in waited {
+            self.answer_waiting(token);
+        }
+
+        // Idle WebSocket connections are checked with ping
+        let mut pings = Vec::new();
+        let mut heartbeats = Vec::new();
+
+        for (token, connection) in self.connections.iter_mut() {
+            // Idle event streams get comment
+            if connection.state == State::Streaming {
+                if connection.last_activity.elapsed() >= self.config.ping_interval {
+                    heartbeats.push(*token);
+                }
+
+                continue;
+            }
+
+            let session = match &mut connection.websocket {
+                Some(e) => e,
+                None => continue,
+            };
+
+            let idle = connection.last_activity.elapsed();
+
+            if session.close_sent {
+                // Client doesn't answer close frame
+                if idle >= self.config.keep_alive_timeout {
+                    expired.push(*token);
+                }
+            } else if let Some(ping_sent) = session.ping_sent {
+                if ping_sent.elapsed() >= self.config.ping_interval {
+                    expired.push(*token);
+                }
+            } else if idle >= self.config.ping_interval {
+                session.ping_sent = Some(Instant::now());
+                pings.push(*token);
+            }
+        }
+
+        for token in pings {
+            self.send(token, &websocket::encode_frame(websocket::PING, &[]));
+        }
+
+        for token in heartbeats {
+            self.send(token, event_stream::HEARTBEAT);
+        }
+
+        for token in expired {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            if let Some(stream) = connection.event_stream.take() {
+                stream.set_closed();
+            }
+
+            // Worker thread stops sending response
+            if let Some((flow, _)) = connection.flow.take() {
+                flow.close();
+            }
+
+            if let Some(waiting) = connection.waiting.take() {
+                waiting.handle.close();
+            }
+
+            // Handler of WebSocket connection is notified about closing
+            if let Some(session) = connection.websocket.take() {
+                session.socket.set_closed();
+
+                let (code, reason) = session.close_status
+                    .unwrap_or((websocket::ABNORMAL_CLOSURE, String::new()));
+
+                if let Err(task) = self.pool.execute(Task::WebSocket(session.socket, session.handler, Event::Close(code, reason))) {
+                    self.reject(task);
+                }
+            }
+
+            connection.shutdown();
+
+            if let Err(error) = self.poll.registry().deregister(connection.stream()) {
+                println!("e: can't deregister connection: {}", error);
+            }
+        }
+    }
+}
+
+/// Maximal size of received data which isn't processed yet (the rest is left in socket until it's needed)
+fn read_limit(config: &Config) -> usize {
+    config.max_headers_size.saturating_add(config.max_body_size.max(config.max_frame_size))
+}
+
+/// Run handler of request (in worker thread)
+fn process_request(request: &mut Request, config: &Config, handlers: &RwLock<Router>, middlewares: &RwLock<Chain>, error_handlers: &RwLock<HashMap<RequestError, Job>>) -> Response {
+    // Handler is taken out of lock to let add new handlers while it works.
+    // HEAD is served by GET's handler if there is no special one
+    let (route, allowed_methods) = {
+        let handlers = handlers.read().unwrap();
+
+        let route = handlers.find(request.method(), request.path())
+            .or_else(|| if request.method() == "HEAD" { handlers.find("GET", request.path()) } else { None });
+
+        (route, handlers.allowed_methods(request.path()))
+    };
+
+    let middlewares = middlewares.read().unwrap().matching(request.path());
+
+    let (handler, options, invalid_params) = match route {
+        Some((handler, options, Some(params))) => {
+            request.set_params(params);
+
+            (Some(handler), options, false)
+        },
+        Some((_, options, None)) => (None, options, true),
+        None => (None, RouteOptions::default(), false),
+    };
+
+    let endpoint = |request: &mut Request| match &handler {
+        Some(handler) => {
+            // Process request and run specified handler
+            let mut response = handler.handle(request);
+
+            // Handler can pass request to error handler
+            match response.take_error() {
+                Some(error) => error_response(&error_handlers.read().unwrap(), error, request),
+                None => range::apply(request, response),
+            }
+        },
+        None if invalid_params => {
+            println!("i: path parameter isn't valid utf-8");
+
+            // 400 error
+            error_response(&error_handlers.read().unwrap(), RequestError::BadRequest, request)
+        },
+        None if !allowed_methods.is_empty() => {
+            let allow = allowed_methods.join(", ");
+
+            if request.method() == "OPTIONS" {
+                Response::new(Status::NoContent).with_header("Allow", &allow)
+            } else {
+                println!("i: method isn't allowed");
+
+                // 405 error
+                error_response(&error_handlers.read().unwrap(), RequestError::MethodNotAllowed, request)
+                    .with_header("Allow", &allow)
+            }
+        },
+        None => {
+            println!("i: handler not found");
+
+            // 404 error
+            error_response(&error_handlers.read().unwrap(), RequestError::NotFound, request)
+        },
+    };
+
+    let context = format!("handler of {} {} from {}", request.method(), request.path(), request.peer_addr());
+
+    // Middlewares are run for unknown paths too (e.g. to log them).
+    // Panic is answered with 500, so client doesn't get closed connection
+    let response = catch_panic(&context, || Next::new(&middlewares, &endpoint).run(request));
+
+    let mut response = match response {
+        Some(e) => e,
+        None => error_response(&error_handlers.read().unwrap(), RequestError::InternalServerError, request),
+    };
+
+    // Middleware can pass request to error handler
+    if let Some(error) = response.take_error() {
+        response = error_response(&error_handlers.read().unwrap(), error, request);
+    }
+
+    let mut response = if options.compress {
+        compression::apply(request, response, config.compression_threshold)
+    } else {
+        response
+    };
+
+    if let Some(hsts) = hsts(config) {
+        response.set_header("Strict-Transport-Security", hsts);
+    }
+
+    response
+}
+
+/// Value of `Strict-Transport-Security` for responses of main listener (it's sent only over HTTPS)
+fn hsts(config: &Config) -> Option<&String> {
+    #[cfg(feature = "tls")]
+    let secure = config.tls.is_some();
+
+    #[cfg(not(feature = "tls"))]
+    let secure = false;
+
+    config.hsts.as_ref().filter(|_| secure)
+}
+
+/// Redirect request to HTTPS or serve it from `/.well-known/` directory (in worker thread)
+fn process_redirect(request: &Request, redirect: &Redirect, error_handlers: &RwLock<HashMap<RequestError, Job>>) -> Response {
+    let mut response = redirect.handle(request);
+
+    match response.take_error() {
+        Some(error) => error_response(&error_handlers.read().unwrap(), error, request),
+        None => response,
+    }
+}
+
+/// Run error handler (or make default response, also if error handler was panicked)
+fn error_response(error_handlers: &HashMap<RequestError, Job>, error: RequestError, request: &Request) -> Response {
+    let response = match error_handlers.get(&error) {
+        Some(handler) => catch_panic(&format!("error handler of {:?}", error), || handler.handle(request)),
+        None => None,
+    };
+
+    response.unwrap_or_else(|| Response::new(error.status()))
+}
+
+/// Run code of user and log its panic (`None` means that it was panicked)
+fn catch_panic<T, F: FnOnce() -> T>(context: &str, f: F) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(e) => Some(e),
+        Err(payload) => {
+            println!("e: {} was panicked: {}", context, panic_message(payload.as_ref()));
+            None
+        },
+    }
+}
+
+/// Message which was passed to `panic!`
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Serialize response to bytes
+fn serialize(response: Response, keep_alive: bool, head_request: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    if let Err(error) = response.write_to(&mut data, keep_alive, head_request, true) {
+        println!("e: problems with writing of response: {}", error);
+    }
+
+    data
+}
+
+/// Writer which passes response from worker thread to event loop by parts
+/// (it waits while client doesn't read the previous ones and fails when connection is closed)
+struct ResponseWriter<'a> {
+    token: Token,
+    controller_tx: &'a Sender<Impulse>,
+    waker: &'a Waker,
+    buffer: Vec<u8>,
+    flow: Arc<Flow>,
+}
+
+impl ResponseWriter<'_> {
+    /// Pass the last part of response
+    fn finish(mut self, keep_alive: bool) {
+        let data = mem::take(&mut self.buffer);
+
+        // Event loop is stopped, so there is nobody to send response
+        let _ = self.send(Impulse::Response(self.token, data, keep_alive));
+    }
+
+    fn send(&self, impulse: Impulse) -> io::Result<()> {
+        self.controller_tx.send(impulse)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.waker.wake()
+    }
+}
+
+impl Write for ResponseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.flow.is_closed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.buffer.extend_from_slice(buf);
+
+        if self.buffer.len() >= PART_SIZE {
+            self.flush()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let data = mem::take(&mut self.buffer);
+
+        self.flow.acquire(data.len(), MAX_UNSENT_SIZE)?;
+        self.send(Impulse::Data(self.token, data, Arc::clone(&self.flow)))
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use mio::{Token, Waker};
+
+use super::Impulse;
+
+/// Comment which keeps idle stream alive for proxies
+pub(super) const HEARTBEAT: &[u8] = b":\n\n";
+
+/// Event of `text/event-stream`
+#[derive(Debug, Clone, Default)]
+pub struct ServerEvent {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl ServerEvent {
+    /// Create event with data (multiline data is sent as several `data` fields)
+    pub fn new(data: &str) -> ServerEvent {
+        ServerEvent {
+            data: data.to_string(),
+            ..ServerEvent::default()
+        }
+    }
+
+    /// Set id which client sends back in `Last-Event-ID` on reconnect
+    pub fn with_id(mut self, id: &str) -> ServerEvent {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Set type of event (`message` is used by client if it isn't set)
+    pub fn with_event(mut self, event: &str) -> ServerEvent {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// Set time which client waits for before reconnect
+    pub fn with_retry(mut self, retry: Duration) -> ServerEvent {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Serialize event to fields of stream
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = String::new();
+
+        // Line breaks can't be inside of single-line fields
+        let clean = |value: &str| value.replace(['\r', '\n', '\0'], "");
+
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", clean(id)));
+        }
+
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", clean(event)));
+        }
+
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            encoded.push_str(&format!("data: {}\n", line));
+        }
+
+        encoded.push('\n');
+        encoded.into_bytes()
+    }
+}
+
+/// Handle of connection with `text/event-stream` response which can be cloned and used to send events from any thread
+#[derive(Clone)]
+pub struct EventStream {
+    token: Token,
+    controller_tx: Sender<Impulse>,
+    waker: Arc<Waker>,
+    open: Arc<AtomicBool>,
+}
+
+impl EventStream {
+    pub(super) fn new(token: Token, controller_tx: Sender<Impulse>, waker: Arc<Waker>) -> EventStream {
+        EventStream {
+            token,
+            controller_tx,
+            waker,
+            open: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Send event (error means that connection is closed)
+    pub fn send(&self, event: &ServerEvent) -> io::Result<()> {
+        if !self.is_open() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.impulse(Impulse::Frame(self.token, event.encode()))
+    }
+
+    /// Finish response and close connection
+    pub fn close(&self) -> io::Result<()> {
+        if !self.open.swap(false, Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.impulse(Impulse::End(self.token))
+    }
+
+    /// Connection isn't closed
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn set_closed(&self) {
+        self.open.store(false, Ordering::SeqCst);
+    }
+
+    /// Send impulse to event loop and wake it up
+    pub(super) fn impulse(&self, impulse: Impulse) -> io::Result<()> {
+        self.controller_tx.send(impulse)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.waker.wake()
+    }
+}
@@ -0,0 +1,114 @@
+use std::slice::Iter;
+
+/// Params of `application/x-www-form-urlencoded` data (query string or form body).
+/// Repeated keys are kept in order of their appearance.
+#[derive(Debug, Clone, Default)]
+pub struct Form {
+    pairs: Vec<(String, String)>,
+}
+
+impl Form {
+    pub fn new() -> Form {
+        Form {
+            pairs: Vec::new(),
+        }
+    }
+
+    /// Parse urlencoded data (`+` is space and `%XX` is byte, invalid sequences are kept as is)
+    pub fn parse(data: &str) -> Form {
+        let pairs = data.split('&')
+            .filter(|e| !e.is_empty())
+            .map(|e| {
+                // Only the first `=` separates key and value
+                let (key, value) = e.split_once('=').unwrap_or((e, ""));
+
+                (decode(key), decode(value))
+            })
+            .collect();
+
+        Form {
+            pairs,
+        }
+    }
+
+    /// Value of the first param with key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Values of all params with key
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.pairs.iter()
+            .filter(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Params in order of their appearance
+    pub fn iter(&self) -> Iter<'_, (String, String)> {
+        self.pairs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Form {
+    type Item = &'a (String, String);
+    type IntoIter = Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Decode percent-encoded path (`None` if it isn't valid utf-8)
+pub(super) fn decode_path(path: &str) -> Option<String> {
+    String::from_utf8(decode_bytes(path, false)).ok()
+}
+
+/// Decode urlencoded string (invalid utf-8 is replaced with U+FFFD)
+fn decode(data: &str) -> String {
+    String::from_utf8_lossy(&decode_bytes(data, true)).into_owned()
+}
+
+/// Decode `%XX` sequences (and `+` if `plus_as_space` is set) to bytes
+fn decode_bytes(data: &str, plus_as_space: bool) -> Vec<u8> {
+    let bytes = data.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => decoded.push(b' '),
+            b'%' => match (bytes.get(i + 1).and_then(|e| hex(*e)), bytes.get(i + 2).and_then(|e| hex(*e))) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high * 16 + low);
+                    i += 2;
+                },
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+
+        i += 1;
+    }
+
+    decoded
+}
+
+/// Value of hexadecimal digit
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|e| e as u8)
+}
@@ -0,0 +1,112 @@
+use std::slice::Iter;
+
+use super::RequestError;
+
+/// Header fields with case-insensitive names.
+/// Repeated fields (`Cookie`, `Accept` and etc.) are kept separately in order of their receiving.
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Value of the first field with name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Values of all fields with name
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set field (fields with the same name are replaced)
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Add field (fields with the same name are kept)
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Fields in order of their adding (names are kept as they were received)
+    pub fn iter(&self) -> Iter<'_, (String, String)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Parse and validate header line (`Name: value`) and add it.
+    /// Folded lines (obs-fold), names with whitespace or control characters are rejected.
+    pub(super) fn parse_line(&mut self, line: &str) -> Result<(), RequestError> {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            println!("e: obsolete line folding in header {:?}", line);
+            return Err(RequestError::BadRequest);
+        }
+
+        let (name, value) = match line.split_once(':') {
+            Some(e) => e,
+            None => {
+                println!("e: header line has no colon {:?}", line);
+                return Err(RequestError::BadRequest);
+            },
+        };
+
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            println!("e: invalid header name {:?}", name);
+            return Err(RequestError::BadRequest);
+        }
+
+        let value = value.trim_matches(|e| e == ' ' || e == '\t');
+
+        if value.chars().any(|e| e.is_control() && e != '\t') {
+            println!("e: invalid value of header {:?}", name);
+            return Err(RequestError::BadRequest);
+        }
+
+        self.append(name, value);
+
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a (String, String);
+    type IntoIter = Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Check if character is allowed in header name (token of RFC 7230)
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
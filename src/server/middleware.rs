@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use super::{Request, Response};
+
+/// Code which runs around handlers (closures `Fn(&mut Request, &Next) -> Response` are middlewares too).
+/// Middleware can change request before `next.run`, change response after it or return its own response
+/// with no call of `next.run`.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: &Next) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, &Next) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: &Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of chain (the next middlewares and handler)
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&mut Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(super) fn new(middlewares: &'a [Arc<dyn Middleware>], endpoint: &'a dyn Fn(&mut Request) -> Response) -> Next<'a> {
+        Next {
+            middlewares,
+            endpoint,
+        }
+    }
+
+    /// Pass request to the next middleware (or handler if it's the last one)
+    pub fn run(&self, request: &mut Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, &Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// Middlewares in order of their adding with prefixes of paths which they are run for
+pub(super) struct Chain {
+    middlewares: Vec<(String, Arc<dyn Middleware>)>,
+}
+
+impl Chain {
+    pub(super) fn new() -> Chain {
+        Chain {
+            middlewares: Vec::new(),
+        }
+    }
+
+    pub(super) fn insert(&mut self, prefix: &str, middleware: Box<dyn Middleware>) {
+        self.middlewares.push((prefix.trim_end_matches('/').to_string(), Arc::from(middleware)));
+    }
+
+    /// Middlewares of path (prefix matches whole segments, so `/api` is run for `/api/users` but not for `/apis`)
+    pub(super) fn matching(&self, path: &str) -> Vec<Arc<dyn Middleware>> {
+        self.middlewares.iter()
+            .filter(|(prefix, _)| prefix.is_empty() || path == prefix || path.strip_prefix(prefix.as_str()).map(|e| e.starts_with('/')).unwrap_or(false))
+            .map(|(_, middleware)| Arc::clone(middleware))
+            .collect()
+    }
+}
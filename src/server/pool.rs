@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Time which is given to idle workers to stop
+const JOIN_GRACE: Duration = Duration::from_millis(100);
+
+/// How often workers are checked while pool is joined
+const JOIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Fixed-size pool of worker threads with bounded queue of tasks
+pub struct Pool<T: Send + 'static> {
+    shared: Arc<Shared<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    available: Condvar,
+}
+
+struct State<T> {
+    /// Tasks which are waiting for free worker with time of their arrival
+    tasks: VecDeque<(T, Instant)>,
+    /// Maximal number of tasks waiting in queue when all workers are busy
+    queue_size: usize,
+    /// Number of workers which are waiting for task
+    idle: usize,
+    shutdown: bool,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Start `size` workers which run `worker` for every task
+    pub fn new<F>(size: usize, queue_size: usize, worker: F) -> Pool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                tasks: VecDeque::new(),
+                queue_size,
+                idle: 0,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        });
+
+        let worker = Arc::new(worker);
+
+        let workers = (0..size).map(|id| {
+            let shared = Arc::clone(&shared);
+            let worker = Arc::clone(&worker);
+
+            thread::spawn(move || {
+                println!("i: worker thread #{} is started", id);
+
+                // Panic of task mustn't reduce number of workers
+                while let Some(task) = shared.next() {
+                    if panic::catch_unwind(AssertUnwindSafe(|| worker(task))).is_err() {
+                        println!("e: task of worker thread #{} was panicked", id);
+                    }
+                }
+
+                println!("i: worker thread #{} is stopped", id);
+            })
+        }).collect();
+
+        Pool {
+            shared,
+            workers,
+        }
+    }
+
+    /// Put task to queue.
+    /// Returns task back if all workers are busy and queue is full.
+    pub fn execute(&self, task: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.tasks.len() >= state.idle + state.queue_size {
+            return Err(task);
+        }
+
+        state.tasks.push_back((task, Instant::now()));
+        self.shared.available.notify_one();
+
+        Ok(())
+    }
+
+    /// Stop workers after queued tasks and wait for them until deadline.
+    /// Returns number of workers which are still busy (they are left detached).
+    pub fn join(mut self, deadline: Instant) -> usize {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+
+        // Idle workers need a moment to notice shutdown even if deadline is passed
+        let deadline = deadline.max(Instant::now() + JOIN_GRACE);
+
+        while self.workers.iter().any(|e| !e.is_finished()) && Instant::now() < deadline {
+            thread::sleep(JOIN_CHECK_INTERVAL);
+        }
+
+        let (finished, busy): (Vec<_>, Vec<_>) = self.workers.drain(..).partition(|e| e.is_finished());
+
+        for worker in finished {
+            if worker.join().is_err() {
+                println!("e: worker thread was panicked");
+            }
+        }
+
+        busy.len()
+    }
+
+    /// Take out tasks which are waiting in queue longer than `timeout`
+    pub fn expire(&self, timeout: Duration) -> Vec<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut expired = Vec::new();
+
+        while let Some((_, arrival)) = state.tasks.front() {
+            if arrival.elapsed() < timeout {
+                break;
+            }
+
+            if let Some((task, _)) = state.tasks.pop_front() {
+                expired.push(task);
+            }
+        }
+
+        expired
+    }
+}
+
+impl<T> Shared<T> {
+    /// Wait for the next task (`None` means pool is stopped)
+    fn next(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+
+        state.idle += 1;
+
+        loop {
+            // Queued tasks are finished before stop
+            if let Some((task, _)) = state.tasks.pop_front() {
+                state.idle -= 1;
+                return Some(task);
+            }
+
+            if state.shutdown {
+                state.idle -= 1;
+                return None;
+            }
+
+            state = self.available.wait(state).unwrap();
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for Pool<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                println!("e: worker thread was panicked");
+            }
+        }
+    }
+}
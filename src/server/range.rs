@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, self};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Body, Request, Response, Status};
+
+/// Maximal number of ranges in request (the whole body is sent for more ones)
+const MAX_RANGES: usize = 16;
+
+/// Counter which makes boundaries of multipart responses unique
+static BOUNDARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Answer `Range` request with part of response's body (206) or with 416 if no part can be sent.
+/// Only successful responses to GET with `Accept-Ranges: bytes` and known length are processed.
+pub(super) fn apply(request: &Request, mut response: Response) -> Response {
+    let range = match request.header("Range") {
+        Some(e) if request.method() == "GET" => e,
+        _ => return response,
+    };
+
+    let accepts_ranges = response.header("Accept-Ranges")
+        .map(|e| e.eq_ignore_ascii_case("bytes"))
+        .unwrap_or(false);
+
+    if response.status() != Status::Ok || !accepts_ranges || !is_fresh(request, &response) {
+        return response;
+    }
+
+    let length = match response.body() {
+        Body::Bytes(body) => body.len() as u64,
+        Body::Stream(_) | Body::File(_) => match response.header("Content-Length").and_then(|e| e.parse().ok()) {
+            Some(e) => e,
+            None => return response,
+        },
+    };
+
+    // Invalid header is ignored
+    let ranges = match parse(range, length) {
+        Some(e) => e,
+        None => return response,
+    };
+
+    if ranges.is_empty() {
+        return Response::new(Status::RangeNotSatisfiable)
+            .with_header("Content-Range", &format!("bytes */{}", length));
+    }
+
+    let body = match response.take_body() {
+        Body::Bytes(body) => Source::Bytes(Cursor::new(body)),
+        Body::Stream(stream) => Source::Stream(stream),
+        Body::File(file) => Source::File(file),
+    };
+
+    response.set_status(Status::PartialContent);
+    response.remove_header("Transfer-Encoding");
+
+    if let [(start, end)] = ranges[..] {
+        response.set_header("Content-Range", &format!("bytes {}-{}/{}", start, end, length));
+        response.set_header("Content-Length", &(end - start + 1).to_string());
+        response.set_body(Body::Stream(Box::new(RangeReader::new(body, vec![
+            Part::Skip(start),
+            Part::Take(end - start + 1),
+        ]))));
+
+        return response;
+    }
+
+    // Several ranges are sent as parts of multipart body
+    let boundary = boundary();
+    let content_type = response.header("Content-Type").map(str::to_string);
+    let mut parts = Vec::new();
+    let mut position = 0;
+    let mut size = 0;
+
+    for (start, end) in ranges {
+        let mut head = format!("\r\n--{}\r\n", boundary);
+
+        if let Some(content_type) = &content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+
+        head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n", start, end, length));
+        size += head.len() as u64 + end - start + 1;
+
+        parts.push(Part::Bytes(Cursor::new(head.into_bytes())));
+        parts.push(Part::Skip(start - position));
+        parts.push(Part::Take(end - start + 1));
+        position = end + 1;
+    }
+
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    size += tail.len() as u64;
+    parts.push(Part::Bytes(Cursor::new(tail.into_bytes())));
+
+    response.set_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary));
+    response.set_header("Content-Length", &size.to_string());
+    response.set_body(Body::Stream(Box::new(RangeReader::new(body, parts))));
+
+    response
+}
+
+/// Check `If-Range` (ranges are sent only if client has the same version of body)
+fn is_fresh(request: &Request, response: &Response) -> bool {
+    let condition = match request.header("If-Range") {
+        Some(e) => e,
+        None => return true,
+    };
+
+    // Entity tag is compared strongly and date must be equal to Last-Modified
+    if condition.starts_with('"') {
+        response.header("ETag") == Some(condition)
+    } else {
+        response.header("Last-Modified") == Some(condition)
+    }
+}
+
+/// Parse `Range` header to sorted list of satisfiable ranges with merged overlapping ones.
+/// Returns `None` if header is invalid or has too many ranges.
+fn parse(header: &str, length: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (start, end) = spec.split_once('-')?;
+
+        let range = match (start.trim(), end.trim()) {
+            // The last bytes (`-500`)
+            ("", suffix) => {
+                let suffix = suffix.parse::<u64>().ok()?;
+
+                if suffix == 0 || length == 0 {
+                    continue;
+                }
+
+                (length.saturating_sub(suffix), length - 1)
+            },
+            // From position to the end (`9500-`)
+            (start, "") => (start.parse::<u64>().ok()?, length.saturating_sub(1)),
+            (start, end) => {
+                let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+
+                if end < start {
+                    return None;
+                }
+
+                (start, end.min(length.saturating_sub(1)))
+            },
+        };
+
+        // Range which starts after the end of body can't be satisfied
+        if range.0 < length {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    Some(merged)
+}
+
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.subsec_nanos())
+        .unwrap_or(0);
+
+    format!("talkback{:08x}{:08x}", nanos, BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Step of reading of ranges from body
+enum Part {
+    /// Data which isn't taken from body (headers of multipart's part)
+    Bytes(Cursor<Vec<u8>>),
+    /// Number of body's bytes to skip
+    Skip(u64),
+    /// Number of body's bytes to send
+    Take(u64),
+}
+
+/// Body which ranges are read from
+enum Source {
+    Bytes(Cursor<Vec<u8>>),
+    /// Stream can't seek, so skipped bytes are read
+    Stream(Box<dyn Read + Send>),
+    File(File),
+}
+
+impl Source {
+    /// Pass bytes which aren't sent
+    fn skip(&mut self, count: u64) -> io::Result<()> {
+        let offset = i64::try_from(count)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        match self {
+            Source::Bytes(bytes) => bytes.seek(SeekFrom::Current(offset)).map(|_| ()),
+            Source::File(file) => file.seek(SeekFrom::Current(offset)).map(|_| ()),
+            Source::Stream(stream) => {
+                let skipped = io::copy(&mut stream.take(count), &mut io::sink())?;
+
+                if skipped < count {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Bytes(bytes) => bytes.read(buf),
+            Source::Stream(stream) => stream.read(buf),
+            Source::File(file) => file.read(buf),
+        }
+    }
+}
+
+/// Reader which sends ranges of body from the beginning to the end
+struct RangeReader {
+    inner: Source,
+    parts: VecDeque<Part>,
+}
+
+impl RangeReader {
+    fn new(inner: Source, parts: Vec<Part>) -> RangeReader {
+        RangeReader {
+            inner,
+            parts: parts.into(),
+        }
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let part = match self.parts.front_mut() {
+                Some(e) => e,
+                None => return Ok(0),
+            };
+
+            match part {
+                Part::Bytes(data) => {
+                    let size = data.read(buf)?;
+
+                    if size > 0 {
+                        return Ok(size);
+                    }
+                },
+                Part::Skip(count) => self.inner.skip(*count)?,
+                Part::Take(count) if *count > 0 => {
+                    let limit = (*count).min(buf.len() as u64) as usize;
+                    let size = self.inner.read(&mut buf[..limit])?;
+
+                    if size == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+
+                    *count -= size as u64;
+                    return Ok(size);
+                },
+                Part::Take(_) => {},
+            }
+
+            self.parts.pop_front();
+        }
+    }
+}
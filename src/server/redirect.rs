@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use super::{Handler, Request, RequestError, Response, StaticFiles, Status};
+
+/// Prefix of paths which are served by redirect listener (ACME challenges and etc.)
+const WELL_KNOWN_PREFIX: &str = "/.well-known/";
+
+/// Plain HTTP listener which redirects all requests to HTTPS with 301.
+/// Only `/.well-known/` paths are served from directory (if it's set).
+#[derive(Clone)]
+pub struct Redirect {
+    /// Address of listener (e.g. `0.0.0.0:80`)
+    pub(super) addr: String,
+    /// Scheme and authority of HTTPS server (`None` if host of request is used)
+    origin: Option<String>,
+    well_known: Option<StaticFiles>,
+}
+
+impl Redirect {
+    pub fn new(addr: &str) -> Redirect {
+        Redirect {
+            addr: addr.to_string(),
+            origin: None,
+            well_known: None,
+        }
+    }
+
+    /// Set HTTPS origin of redirects (e.g. `https://example.com:8443`).
+    /// By default `Host` of request is used with the default port of HTTPS.
+    pub fn with_origin(mut self, origin: &str) -> Redirect {
+        self.origin = Some(origin.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Serve files of directory on `/.well-known/` paths
+    pub fn with_well_known<P: AsRef<Path>>(mut self, root: P) -> Redirect {
+        self.well_known = Some(StaticFiles::new(WELL_KNOWN_PREFIX, root));
+        self
+    }
+
+    /// HTTPS origin for request (`None` if request has no valid host)
+    fn origin(&self, request: &Request) -> Option<String> {
+        if let Some(origin) = &self.origin {
+            return Some(origin.clone());
+        }
+
+        let host = request.header("Host")?.trim();
+
+        // Port of plain HTTP isn't the port of HTTPS (IPv6 address is in brackets)
+        let host = match host.rfind(':') {
+            Some(position) if !host[position..].contains(']') => &host[..position],
+            _ => host,
+        };
+
+        let valid = !host.is_empty() && host.chars()
+            .all(|e| e.is_ascii_alphanumeric() || ['.', '-', '[', ']', ':'].contains(&e));
+
+        if valid {
+            Some(format!("https://{}", host))
+        } else {
+            None
+        }
+    }
+}
+
+impl Handler for Redirect {
+    fn handle(&self, request: &Request) -> Response {
+        if let Some(well_known) = &self.well_known {
+            if request.path().starts_with(WELL_KNOWN_PREFIX) && ["GET", "HEAD"].contains(&request.method()) {
+                return well_known.handle(request);
+            }
+        }
+
+        // Path must be absolute to be appended to origin
+        let origin = match self.origin(request) {
+            Some(e) if request.path().starts_with('/') => e,
+            _ => return Response::from_error(RequestError::BadRequest),
+        };
+
+        let mut location = format!("{}{}", origin, request.path());
+
+        if !request.query_string().is_empty() {
+            location.push('?');
+            location.push_str(request.query_string());
+        }
+
+        Response::new(Status::MovedPermanently).with_header("Location", &location)
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
+use std::str;
+
+use serde::de::DeserializeOwned;
+
+use super::{Config, Form, Headers, RequestError};
+
+/// Request of client which is passed to handlers
+pub struct Request {
+    method: String,
+    path: String,
+    version: String,
+    query_string: String,
+    query: Form,
+    headers: Headers,
+    body: Vec<u8>,
+    peer_addr: SocketAddr,
+    params: HashMap<String, String>,
+}
+
+impl Request {
+    /// Request method (`GET`, `POST` and etc.)
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Path of request with no query string
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Protocol version (`HTTP/1.1` or `HTTP/1.0`)
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Raw query string with no `?` (empty if there is no query)
+    pub fn query_string(&self) -> &str {
+        &self.query_string
+    }
+
+    /// Decoded params of query string
+    pub fn query(&self) -> &Form {
+        &self.query
+    }
+
+    /// Header fields (repeated ones are kept separately)
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Value of the first header with name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Raw bytes of body
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Body as utf-8 text (`None` if body isn't valid utf-8)
+    pub fn body_str(&self) -> Option<&str> {
+        str::from_utf8(&self.body).ok()
+    }
+
+    /// Decoded params of urlencoded body (invalid utf-8 is replaced with U+FFFD)
+    pub fn form(&self) -> Form {
+        Form::parse(&String::from_utf8_lossy(&self.body))
+    }
+
+    /// Deserialize JSON body
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+
+    /// Address of client
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Values of named segments and wildcard of handler's path
+    pub fn params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    /// Value of path parameter by name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Headers which can be changed by middleware before handler
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Set parameter which is passed to handler (e.g. user which was found by middleware)
+    pub fn set_param(&mut self, name: &str, value: &str) {
+        self.params.insert(name.to_string(), value.to_string());
+    }
+
+    /// Request with no data which is passed to error handlers when request can't be parsed
+    pub(super) fn empty(peer_addr: SocketAddr) -> Request {
+        Request {
+            method: String::new(),
+            path: String::new(),
+            version: String::from("HTTP/1.1"),
+            query_string: String::new(),
+            query: Form::new(),
+            headers: Headers::new(),
+            body: Vec::new(),
+            peer_addr,
+            params: HashMap::new(),
+        }
+    }
+
+    pub(super) fn set_params(&mut self, params: HashMap<String, String>) {
+        self.params = params;
+    }
+
+    /// Check if client wants to keep connection open after response
+    pub(super) fn keep_alive(&self) -> bool {
+        let options = self.headers.get_all("Connection").into_iter()
+            .flat_map(|e| e.split(','))
+            .map(str::trim)
+            .collect::<Vec<&str>>();
+
+        if options.iter().any(|e| e.eq_ignore_ascii_case("close")) {
+            false
+        } else if options.iter().any(|e| e.eq_ignore_ascii_case("keep-alive")) {
+            true
+        } else {
+            self.version == "HTTP/1.1"
+        }
+    }
+
+    /// Parse request from the beginning of buffer.
+    /// Returns request and number of consumed bytes or `None` if request isn't received completely.
+    /// Decoded chunks of body are kept in `progress` until the rest of request is received.
+    pub(super) fn parse(buffer: &[u8], config: &Config, peer_addr: SocketAddr, progress: &mut ChunkedProgress) -> Result<Option<(Request, usize)>, RequestError> {
+        // Find the end of headers (empty line)
+        let (head_length, body_start) = match find_head_end(buffer) {
+            Some(e) => e,
+            None if buffer.len() > config.max_headers_size => return Err(RequestError::RequestHeaderFieldsTooLarge),
+            None => return Ok(None),
+        };
+
+        if head_length > config.max_headers_size {
+            return Err(RequestError::RequestHeaderFieldsTooLarge);
+        }
+
+        // Create utf-8 string from headers part of buffer
+        let head = str::from_utf8(&buffer[..head_length])
+            .map_err(|_| RequestError::BadRequest)?;
+
+        // Split headers
+        let mut lines = head.lines().map(|e| e.trim_end_matches('\r'));
+
+        // Split request line to parts (request method, path and version)
+        let request_line = lines.next().unwrap_or("").split(' ').collect::<Vec<&str>>();
+
+        // Save request method, path and version (only HTTP/1.x with exactly three parts is served)
+        let (method, path, version) = match request_line[..] {
+            [method, path, version] if !method.is_empty() && !path.is_empty() && (version == "HTTP/1.0" || version == "HTTP/1.1") => {
+                (method.to_string(), path.to_string(), version.to_string())
+            },
+            _ => {
+                println!("e: request processing error {:?}", head);
+                return Err(RequestError::BadRequest);
+            },
+        };
+
+        let mut headers = Headers::new();
+
+        for line in lines {
+            headers.parse_line(line)?;
+
+            if headers.len() > config.max_headers_number {
+                return Err(RequestError::RequestHeaderFieldsTooLarge);
+            }
+        }
+
+        // Process body part of request (chunked or of Content-Length size)
+        let transfer_encoding = match headers.get_all("Transfer-Encoding") {
+            codings if codings.is_empty() => None,
+            codings => Some(codings.join(", ")),
+        };
+
+        // Repeated Content-Length is allowed only with the same value
+        let content_length = match headers.get_all("Content-Length").as_slice() {
+            [] => None,
+            [first, rest @ ..] if rest.iter().all(|e| e == first) => Some(first.to_string()),
+            _ => {
+                println!("e: different values of Content-Length");
+                return Err(RequestError::BadRequest);
+            },
+        };
+
+        let (body, trailers, consumed) = match (transfer_encoding, content_length) {
+            (Some(_), Some(_)) => {
+                println!("e: both Transfer-Encoding and Content-Length are specified");
+                return Err(RequestError::BadRequest);
+            },
+            (Some(encoding), None) => {
+                if !encoding.eq_ignore_ascii_case("chunked") {
+                    println!("e: unsupported transfer encoding {:?}", encoding);
+                    return Err(RequestError::BadRequest);
+                }
+
+                let (body, trailers, consumed) = match decode_chunked(&buffer[body_start..], config, progress)? {
+                    Some(e) => e,
+                    None => return Ok(None),
+                };
+
+                (body, trailers, body_start + consumed)
+            },
+            (None, Some(value)) => {
+                // Only digits are allowed (`parse` takes sign too, which other servers can read differently)
+                if !value.bytes().all(|e| e.is_ascii_digit()) {
+                    return Err(RequestError::BadRequest);
+                }
+
+                let content_length = value.parse::<usize>().map_err(|_| RequestError::BadRequest)?;
+
+                if content_length > config.max_body_size {
+                    return Err(RequestError::PayloadTooLarge);
+                }
+
+                // Wait for the rest of body (limit can be as big as `usize::MAX`)
+                if buffer.len() < body_start.saturating_add(content_length) {
+                    return Ok(None);
+                }
+
+                (buffer[body_start..body_start + content_length].to_vec(), Vec::new(), body_start + content_length)
+            },
+            (None, None) => (Vec::new(), Vec::new(), body_start),
+        };
+
+        // Trailer fields are merged with headers
+        for line in trailers {
+            headers.parse_line(&line)?;
+
+            if headers.len() > config.max_headers_number {
+                return Err(RequestError::RequestHeaderFieldsTooLarge);
+            }
+        }
+
+        // Split path to path and query params
+        let (path, query_string) = match path.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (path, String::new()),
+        };
+
+        let query = Form::parse(&query_string);
+
+        Ok(Some((
+            Request {
+                method,
+                path,
+                version,
+                query_string,
+                query,
+                headers,
+                body,
+                peer_addr,
+                params: HashMap::new(),
+            },
+            consumed,
+        )))
+    }
+}
+
+/// Find the empty line which separates headers and body.
+/// Returns length of headers part and position of body.
+pub(super) fn find_head_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    for i in 0..buffer.len() {
+        if buffer[i..].starts_with(b"\r\n\r\n") {
+            return Some((i, i + 4));
+        }
+
+        if buffer[i..].starts_with(b"\n\n") {
+            return Some((i, i + 2));
+        }
+    }
+
+    None
+}
+
+/// Decoded chunked body: body, trailer fields and number of consumed bytes
+type ChunkedBody = (Vec<u8>, Vec<String>, usize);
+
+/// Chunks of body which were decoded before the rest of request was received
+#[derive(Default)]
+pub(super) struct ChunkedProgress {
+    body: Vec<u8>,
+    /// Position after the last decoded chunk (from the beginning of body)
+    position: usize,
+}
+
+/// Decode body with chunked transfer coding (decoding is continued from `progress`).
+/// Returns `None` if body isn't received completely.
+fn decode_chunked(buffer: &[u8], config: &Config, progress: &mut ChunkedProgress) -> Result<Option<ChunkedBody>, RequestError> {
+    loop {
+        let position = progress.position;
+
+        // Read chunk size line (chunk extensions are ignored)
+        let line_end = match find_line_end(&buffer[position..]) {
+            Some(e) => position + e,
+            None if buffer.len() - position > config.max_headers_size => return Err(RequestError::BadRequest),
+            None => return Ok(None),
+        };
+
+        let line = str::from_utf8(&buffer[position..line_end])
+            .map_err(|_| RequestError::BadRequest)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+
+        // Only hex digits are allowed (`from_str_radix` takes sign too)
+        if !size.bytes().all(|e| e.is_ascii_hexdigit()) {
+            return Err(RequestError::BadRequest);
+        }
+
+        let size = usize::from_str_radix(size, 16).map_err(|_| RequestError::BadRequest)?;
+
+        let chunk_start = skip_line_end(buffer, line_end);
+
+        // Last chunk is followed by trailer fields and empty line
+        if size == 0 {
+            let mut trailers = Vec::new();
+            let mut position = chunk_start;
+
+            loop {
+                let line_end = match find_line_end(&buffer[position..]) {
+                    Some(e) => position + e,
+                    None if buffer.len() - chunk_start > config.max_headers_size => return Err(RequestError::RequestHeaderFieldsTooLarge),
+                    None => return Ok(None),
+                };
+
+                let line = str::from_utf8(&buffer[position..line_end])
+                    .map_err(|_| RequestError::BadRequest)?;
+
+                position = skip_line_end(buffer, line_end);
+
+                if line.is_empty() {
+                    let body = mem::take(progress).body;
+                    return Ok(Some((body, trailers, position)));
+                }
+
+                trailers.push(line.to_string());
+            }
+        }
+
+        // Size comes from client, so it's compared with no overflow
+        // (limit can be lowered by reload after some chunks are decoded)
+        if progress.body.len().checked_add(size).map(|e| e > config.max_body_size).unwrap_or(true) {
+            return Err(RequestError::PayloadTooLarge);
+        }
+
+        // Wait for the rest of chunk and its line end
+        if buffer.len() - chunk_start < size.saturating_add(2) {
+            return Ok(None);
+        }
+
+        let chunk_end = chunk_start + size;
+
+        let next = match &buffer[chunk_end..chunk_end + 2] {
+            b"\r\n" => chunk_end + 2,
+            [b'\n', _] => chunk_end + 1,
+            _ => return Err(RequestError::BadRequest),
+        };
+
+        progress.body.extend_from_slice(&buffer[chunk_start..chunk_end]);
+        progress.position = next;
+    }
+}
+
+/// Find position of line end (LF or CRLF) in buffer
+fn find_line_end(buffer: &[u8]) -> Option<usize> {
+    let position = buffer.iter().position(|e| *e == b'\n')?;
+
+    if position > 0 && buffer[position - 1] == b'\r' {
+        Some(position - 1)
+    } else {
+        Some(position)
+    }
+}
+
+/// Get position after line end which starts on `line_end`
+fn skip_line_end(buffer: &[u8], line_end: usize) -> usize {
+    if buffer[line_end] == b'\r' {
+        line_end + 2
+    } else {
+        line_end + 1
+    }
+}
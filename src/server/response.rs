@@ -0,0 +1,421 @@
+use std::fs::File;
+use std::io::{Read, Write, self};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::{Deferred, EventStream, Headers, Request, RequestError, WebSocketHandler};
+use super::websocket;
+
+/// Maximal size of chunk which is sent with chunked transfer coding
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Status of response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+}
+
+impl Status {
+    /// Numeric code of status
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::SwitchingProtocols => 101,
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::Accepted => 202,
+            Status::NoContent => 204,
+            Status::PartialContent => 206,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::SeeOther => 303,
+            Status::NotModified => 304,
+            Status::TemporaryRedirect => 307,
+            Status::PermanentRedirect => 308,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::RequestTimeout => 408,
+            Status::PayloadTooLarge => 413,
+            Status::RangeNotSatisfiable => 416,
+            Status::UpgradeRequired => 426,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    /// Reason phrase of status
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::Accepted => "Accepted",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::SeeOther => "See Other",
+            Status::NotModified => "Not Modified",
+            Status::TemporaryRedirect => "Temporary Redirect",
+            Status::PermanentRedirect => "Permanent Redirect",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::RequestTimeout => "Request Timeout",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::UpgradeRequired => "Upgrade Required",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+
+    /// Check if response can have body (responses 1xx, 204 and 304 can't)
+    fn has_body(&self) -> bool {
+        let code = self.code();
+
+        !(100..200).contains(&code) && code != 204 && code != 304
+    }
+}
+
+/// Body of response
+pub enum Body {
+    /// Body which is known up front (`Content-Length` is added automatically)
+    Bytes(Vec<u8>),
+    /// Body of unknown length which is sent with chunked transfer coding
+    Stream(Box<dyn Read + Send>),
+    /// File which is read while it is sending like stream (skipped bytes of ranges are passed by seeking)
+    File(File),
+}
+
+/// Protocol which connection is switched to after response
+pub(super) enum Upgrade {
+    WebSocket(Arc<dyn WebSocketHandler>),
+    /// Callback which gets handle of stream after headers are sent
+    EventStream(Box<dyn FnOnce(EventStream) + Send>),
+    /// Response is sent later by handle which callback gets (or before timeout expires)
+    Deferred(Duration, Box<dyn FnOnce(Deferred) + Send>),
+}
+
+/// Response of handler
+pub struct Response {
+    status: Status,
+    headers: Headers,
+    body: Body,
+    /// Error which response of error handler is sent for
+    error: Option<RequestError>,
+    /// Protocol which connection is switched to
+    upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    /// Create response with no headers and empty body
+    pub fn new(status: Status) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+            error: None,
+            upgrade: None,
+        }
+    }
+
+    /// Create response which is replaced with response of error handler
+    pub fn from_error(error: RequestError) -> Response {
+        let mut response = Response::new(error.status());
+        response.error = Some(error);
+        response
+    }
+
+    /// Create response which switches connection to WebSocket served by handler.
+    /// 400 (or 426 for unsupported version) is sent if request isn't valid handshake.
+    pub fn upgrade<H: WebSocketHandler + 'static>(request: &Request, handler: H) -> Response {
+        let accept = match websocket::accept_key(request) {
+            Ok(e) => e,
+            Err(response) => return response,
+        };
+
+        let mut response = Response::new(Status::SwitchingProtocols)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", &accept);
+
+        response.upgrade = Some(Upgrade::WebSocket(Arc::new(handler)));
+        response
+    }
+
+    /// Create `text/event-stream` response which keeps connection open.
+    /// `on_open` gets handle of stream after headers are sent, the handle can be kept to send events later.
+    pub fn event_stream<F: FnOnce(EventStream) + Send + 'static>(on_open: F) -> Response {
+        let mut response = Response::new(Status::Ok)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache");
+
+        response.upgrade = Some(Upgrade::EventStream(Box::new(on_open)));
+        response
+    }
+
+    /// Answer request later with no busy worker thread: request waits in event loop, `on_open` gets handle which
+    /// sends response from any thread (e.g. from callback of event). This response is sent if nothing is sent before `timeout`.
+    pub fn deferred<F: FnOnce(Deferred) + Send + 'static>(mut self, timeout: Duration, on_open: F) -> Response {
+        self.upgrade = Some(Upgrade::Deferred(timeout, Box::new(on_open)));
+        self
+    }
+
+    /// Set header (header with the same name is replaced)
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.set_header(name, value);
+        self
+    }
+
+    /// Set body which is known up front
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Set JSON body and its `Content-Type` (500 is sent if value can't be serialized)
+    pub fn with_json<T: Serialize + ?Sized>(mut self, value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => {
+                self.set_header("Content-Type", "application/json; charset=utf-8");
+                self.body = Body::Bytes(body);
+            },
+            Err(error) => {
+                println!("e: can't serialize JSON body: {}", error);
+                self.status = Status::InternalServerError;
+                self.body = Body::Bytes(Vec::new());
+            },
+        }
+
+        self
+    }
+
+    /// Set body which is read from stream while it is sending
+    pub fn with_stream<R: Read + Send + 'static>(mut self, stream: R) -> Response {
+        self.body = Body::Stream(Box::new(stream));
+        self
+    }
+
+    /// Set body which is read from file while it is sending
+    pub fn with_file(mut self, file: File) -> Response {
+        self.body = Body::File(file);
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    /// Headers in order of their adding
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Value of the first header with name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Set header (header with the same name is replaced)
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.insert(name, value);
+    }
+
+    /// Add header (headers with the same name are kept, e.g. for `Set-Cookie`)
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.append(name, value);
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.remove(name);
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn set_body(&mut self, body: Body) {
+        self.body = body;
+    }
+
+    /// Take body out of response (empty body is left)
+    pub(super) fn take_body(&mut self) -> Body {
+        mem::replace(&mut self.body, Body::Bytes(Vec::new()))
+    }
+
+    /// Take error which response of error handler must be sent for
+    pub(super) fn take_error(&mut self) -> Option<RequestError> {
+        self.error.take()
+    }
+
+    /// Take protocol which connection must be switched to
+    pub(super) fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    /// Send response to writer (with no body for HEAD request).
+    /// `chunked` means that client understands chunked transfer coding (HTTP/1.1).
+    /// Returns `true` if connection can be used for the next request.
+    pub(super) fn write_to<W: Write>(mut self, writer: &mut W, keep_alive: bool, head_request: bool, chunked: bool) -> io::Result<bool> {
+        let has_body = self.status.has_body();
+        let has_length = self.header("Content-Length").is_some();
+
+        // Handler can ask for chunked body by itself
+        let mut use_chunked = self.header("Transfer-Encoding")
+            .map(|e| e.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+
+        // Find the way how client finds the end of body
+        let mut delimited = true;
+
+        if has_body && !use_chunked && !has_length {
+            match &self.body {
+                Body::Bytes(body) => {
+                    let length = body.len().to_string();
+                    self.add_header("Content-Length", &length);
+                },
+                Body::Stream(_) | Body::File(_) if chunked => {
+                    self.add_header("Transfer-Encoding", "chunked");
+                    use_chunked = true;
+                },
+                // The end of body is found by closing of connection
+                Body::Stream(_) | Body::File(_) => delimited = false,
+            }
+        }
+
+        // Connection is closed if client or handler asked for it
+        // or if the end of body can be found only by closing of connection
+        let connection = self.header("Connection").map(|e| e.eq_ignore_ascii_case("close"));
+        let keep_alive = keep_alive && delimited && connection != Some(true);
+
+        if connection.is_none() {
+            self.add_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+        }
+
+        // Write headers
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.code(), self.status.reason());
+
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        // Response to HEAD has only headers which describe body of GET
+        if head_request || !has_body {
+            return Ok(keep_alive);
+        }
+
+        // Write body
+        match (self.body, use_chunked) {
+            (Body::Bytes(body), true) => {
+                let mut writer = ChunkedWriter::new(writer);
+
+                writer.write_all(&body)?;
+                writer.finish()?;
+            },
+            (Body::Bytes(body), false) => writer.write_all(&body)?,
+            (Body::Stream(mut stream), true) => {
+                let mut writer = ChunkedWriter::new(writer);
+
+                io::copy(&mut stream, &mut writer)?;
+                writer.finish()?;
+            },
+            (Body::Stream(mut stream), false) => {
+                io::copy(&mut stream, writer)?;
+            },
+            (Body::File(mut file), true) => {
+                let mut writer = ChunkedWriter::new(writer);
+
+                io::copy(&mut file, &mut writer)?;
+                writer.finish()?;
+            },
+            (Body::File(mut file), false) => {
+                io::copy(&mut file, writer)?;
+            },
+        }
+
+        Ok(keep_alive)
+    }
+}
+
+/// Writer which encodes data with chunked transfer coding
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter {
+            inner,
+        }
+    }
+
+    /// Send last chunk (with no trailers)
+    fn finish(mut self) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Empty chunk means the end of body, so skip it
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let size = buf.len().min(CHUNK_SIZE);
+
+        self.inner.write_all(format!("{:x}\r\n", size).as_bytes())?;
+        self.inner.write_all(&buf[..size])?;
+        self.inner.write_all(b"\r\n")?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
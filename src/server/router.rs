@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{Job, RouteOptions};
+use super::form::decode_path;
+
+/// Handler, its options and percent-decoded path parameters (`None` if some value isn't valid utf-8)
+type Found = (Arc<Job>, RouteOptions, Option<HashMap<String, String>>);
+
+/// Part of route's path
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    /// Segment which must be equal to path's one (`/api`)
+    Static(String),
+    /// Named segment which matches any path's one (`/:id`)
+    Param(String),
+    /// Named tail which matches the rest of path (`/*file`)
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Priority of segment (the less is the more specific)
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+/// Path pattern of handler like `/api/rooms/:id/messages` or `/static/*file`
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub(super) fn parse(path: &str) -> Result<Pattern, String> {
+        if !path.starts_with('/') {
+            return Err(format!("path {:?} must start with '/'", path));
+        }
+
+        let parts = split(path);
+        let mut segments = Vec::new();
+
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                if name.is_empty() {
+                    return Err(format!("parameter in path {:?} has no name", path));
+                }
+
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                if i + 1 != parts.len() {
+                    return Err(format!("wildcard in path {:?} must be the last segment", path));
+                }
+
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Static(part.to_string())
+            };
+
+            segments.push(segment);
+        }
+
+        Ok(Pattern {
+            segments,
+        })
+    }
+
+    /// Match path with pattern and return captured values of parameters
+    fn capture(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(value) => {
+                    if path.get(i) != Some(&value.as_str()) {
+                        return None;
+                    }
+                },
+                Segment::Param(name) => {
+                    match path.get(i) {
+                        Some(value) if !value.is_empty() => {
+                            params.insert(name.clone(), value.to_string());
+                        },
+                        _ => return None,
+                    }
+                },
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), path.get(i..).unwrap_or(&[]).join("/"));
+                    return Some(params);
+                },
+            }
+        }
+
+        if path.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// Priorities of segments to compare patterns which match the same path
+    fn ranks(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+struct Route {
+    method: String,
+    pattern: Pattern,
+    closure: Arc<Job>,
+    options: RouteOptions,
+}
+
+/// Table of handlers which finds the most specific one for request.
+/// Static segments win over parameters and parameters win over wildcards
+/// (segments are compared from left to right).
+pub(super) struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub(super) fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+        }
+    }
+
+    /// Add handler (handler with the same method and pattern is replaced)
+    pub(super) fn insert(&mut self, method: String, pattern: Pattern, closure: Job, options: RouteOptions) {
+        self.routes.retain(|e| e.method != method || e.pattern != pattern);
+        self.routes.push(Route {
+            method,
+            pattern,
+            closure: Arc::new(closure),
+            options,
+        });
+    }
+
+    /// Find handler of request, its options and captured path parameters.
+    /// Values are decoded after matching, so `%2F` doesn't split segments.
+    pub(super) fn find(&self, method: &str, path: &str) -> Option<Found> {
+        let path = split(path);
+
+        self.routes.iter()
+            .filter(|e| e.method == method)
+            .filter_map(|e| e.pattern.capture(&path).map(|params| (e, params)))
+            .min_by_key(|(e, _)| e.pattern.ranks())
+            .map(|(e, params)| (Arc::clone(&e.closure), e.options, decode(params)))
+    }
+
+    /// Methods which can be used with path (HEAD is allowed for GET's paths and OPTIONS for any path).
+    /// Returns empty list if path is unknown.
+    pub(super) fn allowed_methods(&self, path: &str) -> Vec<String> {
+        let path = split(path);
+
+        let mut methods = self.routes.iter()
+            .filter(|e| e.pattern.capture(&path).is_some())
+            .map(|e| e.method.clone())
+            .collect::<Vec<String>>();
+
+        if methods.is_empty() {
+            return methods;
+        }
+
+        if methods.iter().any(|e| e == "GET") {
+            methods.push(String::from("HEAD"));
+        }
+
+        methods.push(String::from("OPTIONS"));
+        methods.sort();
+        methods.dedup();
+
+        methods
+    }
+}
+
+/// Percent-decode values of parameters (`None` if some value isn't valid utf-8)
+fn decode(params: HashMap<String, String>) -> Option<HashMap<String, String>> {
+    params.into_iter()
+        .map(|(name, value)| decode_path(&value).map(|value| (name, value)))
+        .collect()
+}
+
+/// Split path to segments (leading slash is skipped)
+fn split(path: &str) -> Vec<&str> {
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
@@ -0,0 +1,189 @@
+use std::fs::{self, File, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Handler, Request, RequestError, Response, Status};
+use super::form::decode_path;
+
+/// File which is served for directory
+const INDEX_FILE: &str = "index.html";
+
+/// Handler which serves files of directory.
+/// Paths with `..` and symlinks which lead out of directory are answered with 404.
+/// Files have `ETag` and `Last-Modified`, so unchanged ones are answered with 304.
+#[derive(Clone)]
+pub struct StaticFiles {
+    /// URL prefix which is cut from request path
+    prefix: String,
+    root: PathBuf,
+    cache_control: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(prefix: &str, root: P) -> StaticFiles {
+        StaticFiles {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: root.as_ref().to_path_buf(),
+            cache_control: None,
+        }
+    }
+
+    /// Set `Cache-Control` of served files (e.g. `no-cache` or `public, max-age=3600`)
+    pub fn with_cache_control(mut self, value: &str) -> StaticFiles {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    /// Find file of request path (`None` if path is invalid or leads out of root)
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.prefix)?;
+        let relative = decode_path(relative)?;
+
+        let mut file = self.root.clone();
+
+        for segment in relative.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                _ if segment.contains('\\') || segment.contains('\0') => return None,
+                _ => file.push(segment),
+            }
+        }
+
+        // Symlinks are resolved to check that file is still inside root
+        let root = fs::canonicalize(&self.root).ok()?;
+        let file = fs::canonicalize(file).ok()?;
+
+        if file.starts_with(&root) {
+            Some(file)
+        } else {
+            None
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &Request) -> Response {
+        let mut file = match self.resolve(request.path()) {
+            Some(e) => e,
+            None => return Response::from_error(RequestError::NotFound),
+        };
+
+        if file.is_dir() {
+            // Relative links of index page work only if directory's path ends with slash
+            if !request.path().ends_with('/') {
+                return Response::new(Status::MovedPermanently)
+                    .with_header("Location", &format!("{}/", request.path()));
+            }
+
+            file.push(INDEX_FILE);
+        }
+
+        let stream = match File::open(&file) {
+            Ok(e) => e,
+            Err(_) => return Response::from_error(RequestError::NotFound),
+        };
+
+        let metadata = match stream.metadata() {
+            Ok(e) if e.is_file() => e,
+            _ => return Response::from_error(RequestError::NotFound),
+        };
+
+        // Validators of file's version
+        let modified = metadata.modified().ok();
+        let etag = etag(&metadata);
+
+        let mut response = if is_not_modified(request, &etag, modified) {
+            Response::new(Status::NotModified)
+        } else {
+            Response::new(Status::Ok)
+                .with_header("Content-Type", mime_type(&file))
+                .with_header("Content-Length", &metadata.len().to_string())
+                .with_header("Accept-Ranges", "bytes")
+                .with_file(stream)
+        };
+
+        response.set_header("ETag", &etag);
+
+        if let Some(modified) = modified {
+            response.set_header("Last-Modified", &httpdate::fmt_http_date(modified));
+        }
+
+        if let Some(cache_control) = &self.cache_control {
+            response.set_header("Cache-Control", cache_control);
+        }
+
+        response
+    }
+}
+
+/// Entity tag of file which is made of its size and modification time
+fn etag(metadata: &Metadata) -> String {
+    let modified = metadata.modified().ok()
+        .and_then(|e| e.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    format!("\"{:x}-{:x}-{:x}\"", metadata.len(), modified.as_secs(), modified.subsec_nanos())
+}
+
+/// Check conditional headers of request (`If-Modified-Since` is ignored if `If-None-Match` is sent)
+fn is_not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let if_none_match = request.headers().get_all("If-None-Match");
+
+    if !if_none_match.is_empty() {
+        // Weak comparison: `W/` prefix doesn't matter
+        return if_none_match.iter()
+            .flat_map(|e| e.split(','))
+            .map(|e| e.trim())
+            .any(|e| e == "*" || e.trim_start_matches("W/") == etag);
+    }
+
+    let since = match request.header("If-Modified-Since").and_then(|e| httpdate::parse_http_date(e).ok()) {
+        Some(e) => e,
+        None => return false,
+    };
+
+    // Dates of headers have precision of seconds
+    match modified.and_then(|e| e.duration_since(UNIX_EPOCH).ok()) {
+        Some(modified) => modified.as_secs() <= since.duration_since(UNIX_EPOCH).map(|e| e.as_secs()).unwrap_or(0),
+        None => false,
+    }
+}
+
+/// Content type of file by its extension
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
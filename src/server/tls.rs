@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rustls::ServerConfig;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+/// Possible error of certificate loading
+#[derive(Debug)]
+pub enum TlsError {
+    /// File can't be read
+    Io(PathBuf, io::Error),
+    /// File has no certificates
+    NoCertificate(PathBuf),
+    /// File has no private key or it is invalid
+    InvalidKey(PathBuf),
+    /// Certificates and key can't be used together
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Io(path, error) => write!(f, "can't read {}: {}", path.display(), error),
+            TlsError::NoCertificate(path) => write!(f, "no certificates in {}", path.display()),
+            TlsError::InvalidKey(path) => write!(f, "no valid private key in {}", path.display()),
+            TlsError::Rustls(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Certificate with files it was loaded from
+#[derive(Debug)]
+struct Entry {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: Arc<CertifiedKey>,
+}
+
+/// Certificates of server which are chosen by server name of client (SNI).
+/// Certificates can be added or reloaded while server works, new ones are used for new connections.
+#[derive(Debug, Default)]
+pub struct Certificates {
+    /// Certificates by server name (`None` is default one for clients with unknown name)
+    entries: RwLock<HashMap<Option<String>, Entry>>,
+}
+
+impl Certificates {
+    pub fn new() -> Certificates {
+        Certificates::default()
+    }
+
+    /// Load PEM certificate chain and private key for server name (`None` for default certificate).
+    /// Name can be wildcard like `*.example.com`. Certificate with the same name is replaced.
+    pub fn add<P: AsRef<Path>>(&self, server_name: Option<&str>, cert_path: P, key_path: P) -> Result<(), TlsError> {
+        let cert_path = cert_path.as_ref().to_path_buf();
+        let key_path = key_path.as_ref().to_path_buf();
+        let key = load(&cert_path, &key_path)?;
+
+        self.entries.write().unwrap().insert(server_name.map(str::to_ascii_lowercase), Entry {
+            cert_path,
+            key_path,
+            key,
+        });
+
+        Ok(())
+    }
+
+    /// Read files of all certificates again (certificates are left as is if any file is invalid)
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let mut loaded = Vec::new();
+
+        for (name, entry) in self.entries.read().unwrap().iter() {
+            loaded.push((name.clone(), load(&entry.cert_path, &entry.key_path)?));
+        }
+
+        let mut entries = self.entries.write().unwrap();
+
+        for (name, key) in loaded {
+            if let Some(entry) = entries.get_mut(&name) {
+                entry.key = key;
+            }
+        }
+
+        println!("i: certificates were reloaded");
+
+        Ok(())
+    }
+
+    /// Settings of TLS connections which use these certificates
+    pub(super) fn server_config(self: &Arc<Certificates>) -> Result<Arc<ServerConfig>, TlsError> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Rustls)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let entries = self.entries.read().unwrap();
+
+        // Exact name, then wildcard of parent domain, then default certificate
+        let found = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+
+            entries.get(&Some(name)).or_else(|| entries.get(&wildcard))
+        });
+
+        found.or_else(|| entries.get(&None)).map(|e| Arc::clone(&e.key))
+    }
+}
+
+/// Load certificate chain and private key from PEM files
+fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, TlsError> {
+    let certs = fs::read(cert_path).map_err(|e| TlsError::Io(cert_path.to_path_buf(), e))?;
+    let certs = CertificateDer::pem_slice_iter(&certs)
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|_| TlsError::NoCertificate(cert_path.to_path_buf()))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(cert_path.to_path_buf()));
+    }
+
+    let key = fs::read(key_path).map_err(|e| TlsError::Io(key_path.to_path_buf(), e))?;
+    let key = PrivateKeyDer::from_pem_slice(&key)
+        .map_err(|_| TlsError::InvalidKey(key_path.to_path_buf()))?;
+
+    let key = ring::sign::any_supported_type(&key)
+        .map_err(|_| TlsError::InvalidKey(key_path.to_path_buf()))?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(TlsError::Rustls)?;
+
+    Ok(Arc::new(certified))
+}
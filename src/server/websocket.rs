@@ -0,0 +1,381 @@
+use std::io;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use mio::{Token, Waker};
+
+use super::{Impulse, Request, RequestError, Response, Status};
+
+/// Value which is appended to key of client to make `Sec-WebSocket-Accept`
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+pub(super) const PING: u8 = 0x9;
+pub(super) const PONG: u8 = 0xA;
+
+/// Close codes which are used by server
+pub(super) const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const NO_STATUS: u16 = 1005;
+pub(super) const ABNORMAL_CLOSURE: u16 = 1006;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+pub(super) const INTERNAL_ERROR: u16 = 1011;
+pub(super) const TRY_AGAIN_LATER: u16 = 1013;
+
+/// Message of WebSocket connection (fragmented messages are joined)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Processor of WebSocket connection's events.
+/// Methods are run by worker threads, the next message is read only after the previous one is processed.
+pub trait WebSocketHandler: Send + Sync {
+    /// Connection was switched to WebSocket
+    fn on_open(&self, _socket: &WebSocket) {}
+
+    fn on_message(&self, _socket: &WebSocket, _message: WebSocketMessage) {}
+
+    /// Connection was closed (code is 1006 if it was closed with no close frame)
+    fn on_close(&self, _socket: &WebSocket, _code: u16, _reason: &str) {}
+}
+
+/// Handle of WebSocket connection which can be cloned and used to send messages from any thread
+#[derive(Clone)]
+pub struct WebSocket {
+    token: Token,
+    controller_tx: Sender<Impulse>,
+    waker: Arc<Waker>,
+    open: Arc<AtomicBool>,
+}
+
+impl WebSocket {
+    pub(super) fn new(token: Token, controller_tx: Sender<Impulse>, waker: Arc<Waker>) -> WebSocket {
+        WebSocket {
+            token,
+            controller_tx,
+            waker,
+            open: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Send message (error means that connection is closed)
+    pub fn send(&self, message: WebSocketMessage) -> io::Result<()> {
+        if !self.is_open() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let frame = match message {
+            WebSocketMessage::Text(text) => encode_frame(TEXT, text.as_bytes()),
+            WebSocketMessage::Binary(data) => encode_frame(BINARY, &data),
+        };
+
+        self.impulse(Impulse::Frame(self.token, frame))
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send(WebSocketMessage::Text(text.to_string()))
+    }
+
+    /// Start closing handshake (messages can't be sent after it)
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.open.swap(false, Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.impulse(Impulse::Close(self.token, code, reason.to_string()))
+    }
+
+    /// Connection isn't closed and closing handshake isn't started
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn token(&self) -> Token {
+        self.token
+    }
+
+    pub(super) fn set_closed(&self) {
+        self.open.store(false, Ordering::SeqCst);
+    }
+
+    /// Send impulse to event loop and wake it up
+    pub(super) fn impulse(&self, impulse: Impulse) -> io::Result<()> {
+        self.controller_tx.send(impulse)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.waker.wake()
+    }
+}
+
+/// Event which is passed to worker thread
+pub(super) enum Event {
+    Message(WebSocketMessage),
+    Close(u16, String),
+}
+
+/// Frame which was received from client
+pub(super) struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Result of received frame
+pub(super) enum Received {
+    Message(WebSocketMessage),
+    Ping(Vec<u8>),
+    Close(u16, String),
+}
+
+/// State of WebSocket connection in event loop
+pub(super) struct Session {
+    pub(super) socket: WebSocket,
+    pub(super) handler: Arc<dyn WebSocketHandler>,
+    /// Opcode and data of fragmented message which is being received
+    fragments: Option<(u8, Vec<u8>)>,
+    /// Close frame was sent, so only close frame of client is waited for
+    pub(super) close_sent: bool,
+    /// Code and reason which are passed to `on_close`
+    pub(super) close_status: Option<(u16, String)>,
+    /// Time of ping which wasn't answered yet
+    pub(super) ping_sent: Option<Instant>,
+}
+
+impl Session {
+    pub(super) fn new(socket: WebSocket, handler: Arc<dyn WebSocketHandler>) -> Session {
+        Session {
+            socket,
+            handler,
+            fragments: None,
+            close_sent: false,
+            close_status: None,
+            ping_sent: None,
+        }
+    }
+
+    /// Process frame of client (error is close code of protocol violation)
+    pub(super) fn receive(&mut self, frame: Frame, max_message_size: usize) -> Result<Option<Received>, u16> {
+        // Only close frame is waited for after close frame of server
+        if self.close_sent && frame.opcode != CLOSE {
+            return Ok(None);
+        }
+
+        match frame.opcode {
+            CONTINUATION => {
+                let (opcode, mut data) = self.fragments.take().ok_or(PROTOCOL_ERROR)?;
+
+                if data.len() + frame.payload.len() > max_message_size {
+                    return Err(MESSAGE_TOO_BIG);
+                }
+
+                data.extend_from_slice(&frame.payload);
+
+                if frame.fin {
+                    message(opcode, data).map(Some)
+                } else {
+                    self.fragments = Some((opcode, data));
+                    Ok(None)
+                }
+            },
+            TEXT | BINARY => {
+                // The previous fragmented message isn't finished
+                if self.fragments.is_some() {
+                    return Err(PROTOCOL_ERROR);
+                }
+
+                if frame.payload.len() > max_message_size {
+                    return Err(MESSAGE_TOO_BIG);
+                }
+
+                if frame.fin {
+                    message(frame.opcode, frame.payload).map(Some)
+                } else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                    Ok(None)
+                }
+            },
+            CLOSE => {
+                // Empty payload means no status (1005 itself can't be sent in frame)
+                let (code, reason) = match frame.payload.len() {
+                    0 => (NO_STATUS, String::new()),
+                    1 => return Err(PROTOCOL_ERROR),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        let reason = str::from_utf8(&frame.payload[2..]).map_err(|_| INVALID_DATA)?;
+
+                        if !is_valid_code(code) {
+                            return Err(PROTOCOL_ERROR);
+                        }
+
+                        (code, reason.to_string())
+                    },
+                };
+
+                Ok(Some(Received::Close(code, reason)))
+            },
+            PING => Ok(Some(Received::Ping(frame.payload))),
+            PONG => Ok(None),
+            _ => Err(PROTOCOL_ERROR),
+        }
+    }
+}
+
+/// Check handshake of client and make value of `Sec-WebSocket-Accept`.
+/// Returns 400 or 426 (unsupported version) if request isn't valid handshake.
+pub(super) fn accept_key(request: &Request) -> Result<String, Response> {
+    let has_token = |name: &str, token: &str| {
+        request.headers().get_all(name).iter()
+            .flat_map(|e| e.split(','))
+            .any(|e| e.trim().eq_ignore_ascii_case(token))
+    };
+
+    if request.method() != "GET" || request.version() != "HTTP/1.1" || !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(Response::from_error(RequestError::BadRequest));
+    }
+
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::new(Status::UpgradeRequired).with_header("Sec-WebSocket-Version", "13"));
+    }
+
+    // Key is base64 of 16 random bytes
+    let key = request.header("Sec-WebSocket-Key").unwrap_or("").trim();
+
+    match BASE64.decode(key) {
+        Ok(e) if e.len() == 16 => (),
+        _ => return Err(Response::from_error(RequestError::BadRequest)),
+    }
+
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, GUID)).digest().bytes();
+
+    Ok(BASE64.encode(digest))
+}
+
+/// Parse frame from the beginning of buffer.
+/// Returns frame and number of consumed bytes or `None` if frame isn't received completely.
+/// Error is close code of protocol violation.
+pub(super) fn parse_frame(buffer: &[u8], max_frame_size: usize) -> Result<Option<(Frame, usize)>, u16> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0F;
+
+    // No extensions are negotiated, so reserved bits must be empty
+    if buffer[0] & 0x70 != 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+
+    // Frames of client must be masked
+    if buffer[1] & 0x80 == 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+
+    let (length, mut position) = match buffer[1] & 0x7F {
+        126 => match buffer.get(2..4) {
+            Some(e) => (u16::from_be_bytes([e[0], e[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(e) => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(e);
+
+                (u64::from_be_bytes(bytes), 10)
+            },
+            None => return Ok(None),
+        },
+        length => (length as u64, 2),
+    };
+
+    if length > max_frame_size as u64 {
+        return Err(MESSAGE_TOO_BIG);
+    }
+
+    // Control frames can't be fragmented and have small payload
+    if opcode & 0x8 != 0 && (!fin || length > 125) {
+        return Err(PROTOCOL_ERROR);
+    }
+
+    let length = length as usize;
+
+    let mask = match buffer.get(position..position + 4) {
+        Some(e) => [e[0], e[1], e[2], e[3]],
+        None => return Ok(None),
+    };
+
+    position += 4;
+
+    let payload = match buffer.get(position..position + length) {
+        Some(e) => e.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect(),
+        None => return Ok(None),
+    };
+
+    Ok(Some((Frame { fin, opcode, payload }, position + length)))
+}
+
+/// Make unmasked frame of server
+pub(super) fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        },
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        },
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Make close frame (codes which can't be sent are replaced with empty payload)
+pub(super) fn close_frame(code: u16, reason: &str) -> Vec<u8> {
+    if !is_valid_code(code) {
+        return encode_frame(CLOSE, &[]);
+    }
+
+    // Payload of control frame is limited with 125 bytes
+    let mut end = reason.len().min(123);
+
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+    encode_frame(CLOSE, &payload)
+}
+
+/// Check if close code can be sent in close frame
+fn is_valid_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// Make message of data frames
+fn message(opcode: u8, data: Vec<u8>) -> Result<Received, u16> {
+    let message = if opcode == TEXT {
+        WebSocketMessage::Text(String::from_utf8(data).map_err(|_| INVALID_DATA)?)
+    } else {
+        WebSocketMessage::Binary(data)
+    };
+
+    Ok(Received::Message(message))
+}
@@ -1,8 +1,17 @@
 use crate::{message::Message, user::User};
-use std::{collections::HashMap, fs::{self, File}, io::{BufRead, BufReader}};
+use std::{collections::HashMap, fs::{self, File}, io::{self, BufRead, BufReader}};
 
 const USERS_STORAGE: &str = "users.csv";
 
+/// Callback which gets new message and returns `false` when it isn't interested anymore
+type Callback = Box<dyn FnMut(&Message) -> bool + Send>;
+
+struct Subscriber {
+    callback: Callback,
+    /// Subscriber still waits for messages (e.g. its connection isn't closed)
+    is_open: Box<dyn Fn() -> bool + Send>,
+}
+
 #[derive(Debug)]
 pub enum SessionError {
     EmptyLogin,
@@ -19,6 +28,8 @@ pub struct AnonymSession {
 }
 
 impl AnonymSession {
+    // Lines which can't be read are skipped
+    #[allow(clippy::manual_flatten, clippy::useless_conversion)]
     pub fn new() -> AnonymSession {
         let mut users = HashMap::new();
 
@@ -44,6 +55,7 @@ impl AnonymSession {
             users,
             valid_session: ValidSession {
                 messages: Vec::new(),
+                subscribers: Vec::new(),
             },
         }
     }
@@ -91,31 +103,58 @@ impl AnonymSession {
         
         Err(SessionError::AuthFailed)
     }
-}
 
-impl Drop for AnonymSession {
-    fn drop(&mut self) {
+    /// Write users to storage (it's done on drop too)
+    pub fn save(&self) -> io::Result<()> {
         let mut contents = String::new();
 
-        for (_, user) in &self.users {
+        for user in self.users.values() {
             contents = format!("{}{}\n", contents, user.format());
         }
-        
-        fs::write(USERS_STORAGE, contents).expect("Can't write to users storage!");
+
+        fs::write(USERS_STORAGE, contents)
+    }
+}
+
+impl Drop for AnonymSession {
+    fn drop(&mut self) {
+        self.save().expect("Can't write to users storage!");
     }
 }
 
 pub struct ValidSession {
     messages: Vec<Message>,
+    /// Callbacks which get every new message
+    subscribers: Vec<Subscriber>,
 }
 
 impl ValidSession {
-    pub fn add_message(&mut self, login: &str, text: &str) {
+    pub fn add_message(&mut self, login: &str, text: &str) -> &Message {
         self.messages.push(Message::new(
             self.messages.len(), 
             String::from(login), 
             String::from(text))
         );
+
+        let message = &self.messages[self.messages.len() - 1];
+
+        self.subscribers.retain_mut(|subscriber| (subscriber.callback)(message));
+
+        message
+    }
+
+    /// Call subscriber for every new message until it returns `false`.
+    /// Subscribers which aren't open anymore are removed when the next one is added, so quiet session doesn't keep them.
+    pub fn subscribe<O, F>(&mut self, is_open: O, callback: F)
+    where
+        O: Fn() -> bool + Send + 'static,
+        F: FnMut(&Message) -> bool + Send + 'static,
+    {
+        self.subscribers.retain(|subscriber| (subscriber.is_open)());
+        self.subscribers.push(Subscriber {
+            callback: Box::new(callback),
+            is_open: Box::new(is_open),
+        });
     }
 
     pub fn get_messages(&self, offset: usize) -> Vec<Message> {